@@ -0,0 +1,154 @@
+use std::{collections::HashMap, fs, str::FromStr};
+
+use bevy::log::warn;
+
+const CONFIG_DIR: &str = "assets/config";
+
+/// Plain `key = value` settings read from `assets/config/<name>.cfg`.
+/// Blank lines and anything after a `#` are ignored.
+pub struct Config {
+    name: String,
+    values: HashMap<String, String>,
+}
+
+#[allow(dead_code)]
+impl Config {
+    pub fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(format!(
+                "{}.cfg line {}: expected `key = value`, got `{}`",
+                name,
+                number + 1,
+                line
+            ))?;
+            values.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        Ok(Config {
+            name: name.to_string(),
+            values,
+        })
+    }
+
+    /// Loads a config file if there is one. A file that fails to parse is
+    /// reported and treated as missing.
+    pub fn load(name: &str) -> Option<Self> {
+        let text = fs::read_to_string(format!("{}/{}.cfg", CONFIG_DIR, name)).ok()?;
+        match Config::parse(name, &text) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.values.keys()
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    /// Overwrites `target` with the value under `key`, leaving it alone when the
+    /// key is missing or its value does not parse.
+    pub fn read<T: FromStr>(&self, key: &str, target: &mut T) {
+        if let Some(value) = self.values.get(key) {
+            match value.parse() {
+                Ok(v) => *target = v,
+                Err(_) => warn!("{}.cfg: bad value for {}: `{}`", self.name, key, value),
+            }
+        }
+    }
+
+    /// Reads a comma separated list, e.g. `offsets = 0 0, 1 1, -1 -1` with
+    /// `read_list(key, target, parse_pair)`.
+    pub fn read_list<T, F>(&self, key: &str, target: &mut Vec<T>, parse: F)
+    where
+        F: Fn(&str) -> Option<T>,
+    {
+        if let Some(value) = self.values.get(key) {
+            let list: Option<Vec<T>> = value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(&parse)
+                .collect();
+            match list {
+                Some(list) => *target = list,
+                None => warn!("{}.cfg: bad list for {}: `{}`", self.name, key, value),
+            }
+        }
+    }
+}
+
+/// Parses whitespace separated numbers, e.g. `0.5 1`.
+pub fn parse_numbers<T: FromStr + Copy + Default, const N: usize>(item: &str) -> Option<[T; N]> {
+    let mut numbers = [T::default(); N];
+    let mut parts = item.split_whitespace();
+    for number in numbers.iter_mut() {
+        *number = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let text = "# header\n\nsteps = 4  # trailing\n  name=terrace \n";
+        let config = Config::parse("test", text).unwrap();
+        assert_eq!(config.get_str("steps"), Some("4"));
+        assert_eq!(config.get_str("name"), Some("terrace"));
+        assert_eq!(config.keys().count(), 2);
+    }
+
+    #[test]
+    fn parse_reports_the_bad_line() {
+        let error = Config::parse("test", "a = 1\nnot a setting\n")
+            .err()
+            .unwrap();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn read_keeps_the_default_on_a_bad_or_missing_value() {
+        let config = Config::parse("test", "count = many\nscale = 0.5").unwrap();
+        let (mut count, mut scale, mut missing) = (3usize, 1f32, 7u32);
+        config.read("count", &mut count);
+        config.read("scale", &mut scale);
+        config.read("missing", &mut missing);
+        assert_eq!((count, scale, missing), (3, 0.5, 7));
+    }
+
+    #[test]
+    fn read_list_takes_the_whole_list_or_nothing() {
+        let config = Config::parse("test", "good = 0 0, 1 2,\nbad = 0 0, 1").unwrap();
+        let mut points: Vec<[f32; 2]> = Vec::new();
+        config.read_list("good", &mut points, parse_numbers);
+        assert_eq!(points, vec![[0., 0.], [1., 2.]]);
+        config.read_list("bad", &mut points, parse_numbers);
+        assert_eq!(points, vec![[0., 0.], [1., 2.]]);
+    }
+
+    #[test]
+    fn parse_numbers_needs_exactly_n() {
+        assert_eq!(parse_numbers::<u8, 3>("40 130 60"), Some([40, 130, 60]));
+        assert_eq!(parse_numbers::<u8, 3>("40 130"), None);
+        assert_eq!(parse_numbers::<u8, 3>("40 130 60 1"), None);
+        assert_eq!(
+            parse_numbers::<f32, 2>("-inf inf"),
+            Some([f32::NEG_INFINITY, f32::INFINITY])
+        );
+    }
+}
@@ -1,12 +1,16 @@
 use bevy::prelude::*;
 
-use crate::terrain::terrain_startup;
+use crate::terrain::{spawn_lakes, terrain_startup};
 use crate::AppState;
 
 pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(SystemSet::on_enter(AppState::InGame).with_system(terrain_startup));
+        app.add_system_set(
+            SystemSet::on_enter(AppState::InGame)
+                .with_system(terrain_startup)
+                .with_system(spawn_lakes),
+        );
     }
 }
@@ -1,7 +1,10 @@
 use bevy::prelude::*;
 
 use crate::{
-    generation::{cleanup_image, interact_reset_seed_button, setup_image, setup_options},
+    generation::{
+        cleanup_image, interact_noise_option_buttons, interact_reset_seed_button, setup_image,
+        setup_options, update_noise_option_text,
+    },
     AppState,
 };
 
@@ -19,7 +22,9 @@ impl Plugin for GenMenuPlugin {
             .add_system_set(
                 SystemSet::on_update(AppState::GenConfig)
                     .with_system(interact_generate_button)
-                    .with_system(interact_reset_seed_button),
+                    .with_system(interact_reset_seed_button)
+                    .with_system(interact_noise_option_buttons)
+                    .with_system(update_noise_option_text),
             )
             .add_system_set(SystemSet::on_enter(AppState::GenRun).with_system(update_button_text))
             .add_system_set(SystemSet::on_enter(AppState::GenDone).with_system(update_button_text))
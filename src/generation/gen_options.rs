@@ -2,6 +2,7 @@ use bevy::prelude::*;
 
 use crate::{
     generation::{MenuData, HOVERED_BUTTON, NORMAL_BUTTON, PRESSED_BUTTON},
+    map::{FalloffShape, HeightGenerator, MutatorRegistry, NoiseKind, NoiseSettings},
     randstruct::RandStruct,
     AppState,
 };
@@ -12,12 +13,185 @@ pub struct ResetSeedButton;
 #[derive(Component)]
 pub struct SeedText;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NoiseOption {
+    Generator,
+    Algorithm,
+    Octaves,
+    Lacunarity,
+    Persistence,
+    BaseFrequency,
+    Exponent,
+    IslandBias,
+    Falloff,
+    FalloffStrength,
+    FalloffStart,
+    WarpStrength,
+    WarpDepth,
+    Roughness,
+    Tileable,
+}
+
+impl NoiseOption {
+    const ALL: [NoiseOption; 15] = [
+        NoiseOption::Generator,
+        NoiseOption::Algorithm,
+        NoiseOption::Octaves,
+        NoiseOption::Lacunarity,
+        NoiseOption::Persistence,
+        NoiseOption::BaseFrequency,
+        NoiseOption::Exponent,
+        NoiseOption::IslandBias,
+        NoiseOption::Falloff,
+        NoiseOption::FalloffStrength,
+        NoiseOption::FalloffStart,
+        NoiseOption::WarpStrength,
+        NoiseOption::WarpDepth,
+        NoiseOption::Roughness,
+        NoiseOption::Tileable,
+    ];
+
+    fn label(&self, settings: &NoiseSettings) -> String {
+        match self {
+            NoiseOption::Generator => format!("Generator: {}", settings.generator.name()),
+            NoiseOption::Algorithm => match settings.layers.as_slice() {
+                [] => "Noise: none".to_string(),
+                [layer] => format!("Noise: {}", layer.kind.name()),
+                [layer, ..] => format!(
+                    "Noise: {} + {}",
+                    layer.kind.name(),
+                    settings.layers.len() - 1
+                ),
+            },
+            NoiseOption::Octaves => format!("Octaves: {}", settings.octaves),
+            NoiseOption::Lacunarity if !settings.octave_scales.is_empty() => {
+                "Lacunarity: per octave".to_string()
+            }
+            NoiseOption::Persistence if !settings.octave_scales.is_empty() => {
+                "Persistence: per octave".to_string()
+            }
+            NoiseOption::Lacunarity => format!("Lacunarity: {:.2}", settings.lacunarity),
+            NoiseOption::Persistence => format!("Persistence: {:.2}", settings.persistence),
+            NoiseOption::BaseFrequency => format!("Frequency: {:.1}", settings.base_frequency),
+            NoiseOption::Exponent => format!("Exponent: {:.1}", settings.exponent),
+            NoiseOption::IslandBias => format!("Island bias: {:.2}", settings.island_bias),
+            NoiseOption::Falloff => format!("Falloff: {}", settings.falloff.shape.name()),
+            NoiseOption::FalloffStrength => {
+                format!("Falloff strength: {:.2}", settings.falloff.strength)
+            }
+            NoiseOption::FalloffStart => format!("Falloff start: {:.2}", settings.falloff.start),
+            NoiseOption::WarpStrength => format!("Warp: {:.2}", settings.warp_strength),
+            NoiseOption::WarpDepth => format!("Warp depth: {}", settings.warp_depth),
+            NoiseOption::Roughness => format!("Roughness: {:.2}", settings.roughness),
+            NoiseOption::Tileable => {
+                format!("Tileable: {}", if settings.tileable { "on" } else { "off" })
+            }
+        }
+    }
+
+    fn step(&self, settings: &mut NoiseSettings, direction: f64) {
+        match self {
+            NoiseOption::Generator => {
+                let count = HeightGenerator::ALL.len() as f64;
+                let index = HeightGenerator::ALL
+                    .iter()
+                    .position(|g| *g == settings.generator)
+                    .unwrap_or(0);
+                let index = (index as f64 + direction).rem_euclid(count) as usize;
+                settings.generator = HeightGenerator::ALL[index];
+            }
+            NoiseOption::Algorithm => {
+                // cycles the main layer, any extra layers from the config stay as they are
+                if let Some(layer) = settings.layers.first_mut() {
+                    let count = NoiseKind::ALL.len() as f64;
+                    let index = NoiseKind::ALL
+                        .iter()
+                        .position(|k| *k == layer.kind)
+                        .unwrap_or(0);
+                    let index = (index as f64 + direction).rem_euclid(count) as usize;
+                    layer.kind = NoiseKind::ALL[index];
+                }
+            }
+            NoiseOption::Octaves => {
+                settings.octaves = (settings.octaves as f64 + direction).clamp(1., 12.) as usize
+            }
+            // the per octave list from the config gives way to the menu
+            NoiseOption::Lacunarity => {
+                settings.octave_scales.clear();
+                settings.lacunarity = (settings.lacunarity + direction * 0.1).clamp(1., 4.)
+            }
+            NoiseOption::Persistence => {
+                settings.octave_scales.clear();
+                settings.persistence = (settings.persistence + direction * 0.05).clamp(0.05, 1.)
+            }
+            NoiseOption::BaseFrequency => {
+                settings.base_frequency =
+                    (settings.base_frequency + direction * 0.5).clamp(0.5, 20.)
+            }
+            NoiseOption::Exponent => {
+                settings.exponent = (settings.exponent + direction * 0.5).clamp(0.5, 10.)
+            }
+            NoiseOption::IslandBias => {
+                settings.island_bias = (settings.island_bias + direction * 0.05).clamp(0., 2.)
+            }
+            NoiseOption::Falloff => {
+                // curves and masks only come from the config, cycling away from
+                // one starts over at the first built-in shape
+                let shapes = [
+                    FalloffShape::None,
+                    FalloffShape::Square,
+                    FalloffShape::Radial,
+                    FalloffShape::Diamond,
+                    FalloffShape::Superellipse(4.),
+                ];
+                let count = shapes.len() as f64;
+                let index = shapes
+                    .iter()
+                    .position(|s| s.name() == settings.falloff.shape.name())
+                    .unwrap_or(0);
+                let index = (index as f64 + direction).rem_euclid(count) as usize;
+                settings.falloff.shape = shapes[index].clone();
+            }
+            NoiseOption::FalloffStrength => {
+                settings.falloff.strength =
+                    (settings.falloff.strength + direction * 0.1).clamp(0., 3.)
+            }
+            NoiseOption::FalloffStart => {
+                settings.falloff.start = (settings.falloff.start + direction * 0.05).clamp(0., 0.95)
+            }
+            NoiseOption::WarpStrength => {
+                settings.warp_strength = (settings.warp_strength + direction * 0.05).clamp(0., 2.)
+            }
+            NoiseOption::WarpDepth => {
+                settings.warp_depth =
+                    (settings.warp_depth as f64 + direction).clamp(0., 4.) as usize
+            }
+            NoiseOption::Roughness => {
+                settings.roughness = (settings.roughness + direction * 0.05).clamp(0.05, 1.)
+            }
+            NoiseOption::Tileable => settings.tileable = !settings.tileable,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct NoiseOptionButton {
+    option: NoiseOption,
+    direction: f64,
+}
+
+#[derive(Component)]
+pub struct NoiseOptionText(NoiseOption);
+
 pub fn setup_options(
     mut commands: Commands,
     menu_data: Res<MenuData>,
     asset_server: Res<AssetServer>,
     rand: Res<RandStruct>,
+    noise_settings: Res<NoiseSettings>,
+    registry: Res<MutatorRegistry>,
 ) {
+    let font = asset_server.load("fonts/FiraSans-Bold.ttf");
     commands
         .spawn_bundle(NodeBundle {
             style: Style {
@@ -45,7 +219,7 @@ pub fn setup_options(
                     text: Text::with_section(
                         format!("Seed: {}", rand.map_seed()),
                         TextStyle {
-                            font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                            font: font.clone(),
                             font_size: 20.0,
                             color: Color::rgb(0.9, 0.9, 0.9),
                         },
@@ -85,7 +259,7 @@ pub fn setup_options(
                         text: Text::with_section(
                             "New Seed",
                             TextStyle {
-                                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                                font: font.clone(),
                                 font_size: 20.0,
                                 color: Color::rgb(0.9, 0.9, 0.9),
                             },
@@ -94,9 +268,128 @@ pub fn setup_options(
                         ..Default::default()
                     });
                 });
+            for option in NoiseOption::ALL {
+                spawn_noise_option(parent, option, noise_settings.as_ref(), font.clone());
+            }
+            let stages: Vec<String> = registry
+                .names()
+                .map(|name| match registry.mask(name) {
+                    Some(_) => format!("{} (masked)", name),
+                    None => name.to_string(),
+                })
+                .collect();
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    format!("Stages: {}", stages.join(", ")),
+                    TextStyle {
+                        font: font.clone(),
+                        font_size: 16.0,
+                        color: Color::rgb(0.7, 0.7, 0.7),
+                    },
+                    Default::default(),
+                ),
+                ..Default::default()
+            });
+        });
+}
+
+fn spawn_noise_option(
+    parent: &mut ChildBuilder,
+    option: NoiseOption,
+    noise_settings: &NoiseSettings,
+    font: Handle<Font>,
+) {
+    let text_style = TextStyle {
+        font,
+        font_size: 20.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    parent
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Px(30.)),
+                flex_direction: FlexDirection::Row,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        flex_grow: 1.,
+                        ..Default::default()
+                    },
+                    text: Text::with_section(
+                        option.label(noise_settings),
+                        text_style.clone(),
+                        Default::default(),
+                    ),
+                    ..Default::default()
+                })
+                .insert(NoiseOptionText(option));
+            for (label, direction) in [("-", -1.), ("+", 1.)] {
+                parent
+                    .spawn_bundle(ButtonBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(30.), Val::Px(30.)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        color: NORMAL_BUTTON.into(),
+                        ..Default::default()
+                    })
+                    .insert(NoiseOptionButton { option, direction })
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            text: Text::with_section(label, text_style.clone(), Default::default()),
+                            ..Default::default()
+                        });
+                    });
+            }
         });
 }
 
+pub fn interact_noise_option_buttons(
+    mut interaction_query: Query<
+        (&Interaction, &mut UiColor, &NoiseOptionButton),
+        Changed<Interaction>,
+    >,
+    mut noise_settings: ResMut<NoiseSettings>,
+) {
+    for (interaction, mut color, button) in interaction_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => {
+                button
+                    .option
+                    .step(noise_settings.as_mut(), button.direction);
+                *color = PRESSED_BUTTON.into();
+            }
+            Interaction::Hovered => {
+                *color = HOVERED_BUTTON.into();
+            }
+            Interaction::None => {
+                *color = NORMAL_BUTTON.into();
+            }
+        }
+    }
+}
+
+pub fn update_noise_option_text(
+    noise_settings: Res<NoiseSettings>,
+    mut text_query: Query<(&mut Text, &NoiseOptionText)>,
+) {
+    if !noise_settings.is_changed() {
+        return;
+    }
+    for (mut text, option) in text_query.iter_mut() {
+        text.sections[0].value = option.0.label(noise_settings.as_ref());
+    }
+}
+
 pub fn interact_reset_seed_button(
     mut state: ResMut<State<AppState>>,
     mut interaction_query: Query<
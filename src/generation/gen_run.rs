@@ -1,8 +1,13 @@
-use bevy::prelude::*;
+use bevy::{asset::LoadState, ecs::system::SystemParam, prelude::*};
 
 use crate::{
     generation::{ImageData, ProgressBar},
-    map::{average_by_neighbor, zero_edges, BitImage, PerlinNoise},
+    map::{
+        find_features, BiomeTable, Biomes, BitImage, Boundary, Climate, ClimateSettings,
+        FalloffMask, FalloffShape, FeatureSettings, LakeSettings, Lakes, MutatorContext,
+        MutatorRegistry, NoiseSettings, RegionSettings, Regions, ReverseRain, ReverseRainSettings,
+        RiverSettings, Rivers,
+    },
     terrain::{terrain_build, TerrainMesh, TerrainSettings},
     AppState, RandStruct,
 };
@@ -13,14 +18,31 @@ pub struct Tracker {
     pub current_stage: u32,
     pub current_step_progress: f32,
     pub max_stage: u32,
+    current_step_work: usize,
+    current_step_total: usize,
 }
 
 impl Plugin for GenRunPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Tracker>()
+            .insert_resource(NoiseSettings::load())
+            .insert_resource(MutatorRegistry::load())
+            .insert_resource(RegionSettings::load())
+            .insert_resource(RiverSettings::load())
+            .insert_resource(LakeSettings::load())
+            .insert_resource(ClimateSettings::load())
+            .insert_resource(BiomeTable::load())
+            .init_resource::<ReverseRainSettings>()
             .add_system_set(
                 SystemSet::on_update(AppState::GenRun)
-                    .with_system(generation_main.before("last"))
+                    .with_system(generation_main.label("main").before("last"))
+                    .with_system(
+                        ReverseRain::run_check
+                            .label("rain_check")
+                            .after("main")
+                            .before("last"),
+                    )
+                    .with_system(ReverseRain::run_mutate.after("rain_check").before("last"))
                     .with_system(update_progress_bar.label("last"))
                     .with_system(update_image.label("last")),
             )
@@ -34,64 +56,272 @@ impl Default for Tracker {
         Tracker {
             current_stage: 0,
             current_step_progress: 0.,
-            max_stage: 5,
+            max_stage: 1,
+            current_step_work: 0,
+            current_step_total: 0,
         }
     }
 }
 
 /////////////// main function for generation
 
+#[allow(clippy::too_many_arguments)]
 fn generation_main(
-    tracker: ResMut<Tracker>,
-    heightmap: ResMut<BitImage>,
+    commands: Commands,
+    mut tracker: ResMut<Tracker>,
+    mut heightmap: ResMut<BitImage>,
     terrain_settings: Res<TerrainSettings>,
     terrain_data: Res<TerrainMesh>,
     meshes: ResMut<Assets<Mesh>>,
     state: ResMut<State<AppState>>,
     rand: ResMut<RandStruct>,
+    mut stage: StageParams,
+    mut last_stage: Local<u32>,
 ) {
+    if *last_stage != tracker.current_stage {
+        // keeps the seam of a tileable map exact, whatever the last stage did
+        heightmap.match_edges();
+        heightmap.recompute_bounds();
+        *last_stage = tracker.current_stage;
+    }
+    // setup, then every registered mutator in registry order, then the rest
+    let mutators = stage.registry.names().count() as u32;
     match tracker.current_stage {
-        0 => run_test(tracker),
-        1 => run_perlin_noise(heightmap, rand, terrain_settings, tracker),
-        2 => run_averaging(heightmap, terrain_settings, tracker),
-        3 => run_clean_edges(heightmap, terrain_settings, tracker),
-        4 => terrain_build(
-            terrain_settings,
-            terrain_data,
-            heightmap.as_ref(),
-            meshes,
+        0 => run_setup(
+            commands,
+            heightmap,
+            stage.noise_settings,
+            stage.registry,
+            &mut stage.falloff_mask,
             tracker,
         ),
-        _ => end_generation(state),
+        s if s <= mutators => match stage_name(&stage.registry, s) {
+            Some(ReverseRainSettings::NAME) => run_reverse_rain(
+                commands,
+                rand,
+                stage.rain_settings,
+                stage.drops,
+                terrain_settings,
+                tracker,
+            ),
+            name => run_mutator(
+                name.unwrap_or_default(),
+                heightmap,
+                rand,
+                &mut stage.registry,
+                stage.falloff_mask.mask(),
+                terrain_settings,
+                tracker,
+            ),
+        },
+        s => match s - mutators {
+            1 => run_sea_level(commands, heightmap, terrain_settings, tracker),
+            2 => run_rivers(
+                commands,
+                heightmap,
+                rand,
+                stage.river_settings,
+                terrain_settings,
+                tracker,
+            ),
+            3 => run_lakes(
+                commands,
+                heightmap,
+                stage.lake_settings,
+                terrain_settings,
+                tracker,
+            ),
+            4 => run_climate(
+                commands,
+                heightmap,
+                stage.climate_settings,
+                terrain_settings,
+                tracker,
+            ),
+            5 => match stage.climate {
+                Some(climate) => run_biomes(
+                    commands,
+                    heightmap,
+                    climate,
+                    stage.biome_table,
+                    terrain_settings,
+                    tracker,
+                ),
+                None => {
+                    warn!("No climate to classify biomes with");
+                    tracker.add_progress(100.);
+                }
+            },
+            6 => run_feature_detection(commands, heightmap, tracker),
+            7 => run_regions(
+                commands,
+                heightmap,
+                rand,
+                stage.region_settings,
+                terrain_settings,
+                tracker,
+            ),
+            8 => terrain_build(
+                terrain_settings,
+                terrain_data,
+                heightmap.as_ref(),
+                meshes,
+                tracker,
+            ),
+            _ => end_generation(state),
+        },
+    }
+}
+
+/// Registry name of stage `s`, counting from 1 after the setup stage.
+fn stage_name(registry: &MutatorRegistry, s: u32) -> Option<&'static str> {
+    registry.names().nth(s as usize - 1)
+}
+
+/// Stages after the mutators: sea level, rivers, lakes, climate, biomes,
+/// features, regions and the terrain mesh.
+const LATE_STAGES: u32 = 8;
+
+/// Settings and queries used by single stages, bundled so `generation_main`
+/// stays under the system parameter limit.
+#[derive(SystemParam)]
+pub struct StageParams<'w, 's> {
+    noise_settings: Res<'w, NoiseSettings>,
+    registry: ResMut<'w, MutatorRegistry>,
+    rain_settings: Res<'w, ReverseRainSettings>,
+    drops: Query<'w, 's, &'static ReverseRain>,
+    region_settings: Res<'w, RegionSettings>,
+    river_settings: Res<'w, RiverSettings>,
+    lake_settings: Res<'w, LakeSettings>,
+    climate_settings: Res<'w, ClimateSettings>,
+    /// Inserted by the climate stage, a frame before the biome stage runs.
+    climate: Option<Res<'w, Climate>>,
+    biome_table: Res<'w, BiomeTable>,
+    falloff_mask: FalloffMaskLoader<'w, 's>,
+}
+
+/// Loads the image behind `FalloffShape::Mask` and keeps it for the
+/// mutators of the current run.
+#[derive(SystemParam)]
+pub struct FalloffMaskLoader<'w, 's> {
+    images: Res<'w, Assets<Image>>,
+    asset_server: Res<'w, AssetServer>,
+    handle: Local<'s, Option<Handle<Image>>>,
+    loaded: Local<'s, Option<FalloffMask>>,
+}
+
+impl FalloffMaskLoader<'_, '_> {
+    /// Returns `false` while the mask is still loading. Other shapes, and
+    /// masks that fail to load, leave no mask.
+    fn load(&mut self, shape: &FalloffShape) -> bool {
+        *self.loaded = None;
+        let path = match shape {
+            FalloffShape::Mask(path) => path,
+            _ => return true,
+        };
+        let handle: Handle<Image> = self.asset_server.load(path.as_str());
+        *self.handle = Some(handle.clone());
+        match self.asset_server.get_load_state(&handle) {
+            LoadState::Loaded => {
+                *self.loaded = self.images.get(&handle).and_then(FalloffMask::from_image);
+                true
+            }
+            LoadState::Failed => {
+                error!("Could not load falloff mask: {}", path);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mask(&self) -> Option<&FalloffMask> {
+        self.loaded.as_ref()
     }
 }
 
 /////////////// start: run functions for generation
 
-fn run_test(mut tracker: ResMut<Tracker>) {
+/// Picks the boundary mode and hands the noise settings, as they were left in
+/// the menu, to the registry, once the falloff mask is loaded. Biomes of the
+/// last map are dropped so the preview shows heights until the new ones are in.
+fn run_setup(
+    mut commands: Commands,
+    mut heightmap: ResMut<BitImage>,
+    noise_settings: Res<NoiseSettings>,
+    mut registry: ResMut<MutatorRegistry>,
+    falloff_mask: &mut FalloffMaskLoader,
+    mut tracker: ResMut<Tracker>,
+) {
+    // tileable maps go without a falloff
+    let shape = if noise_settings.tileable {
+        &FalloffShape::None
+    } else {
+        &noise_settings.falloff.shape
+    };
+    if !falloff_mask.load(shape) {
+        return;
+    }
+    heightmap.set_boundary(if noise_settings.tileable {
+        Boundary::Wrap
+    } else {
+        Boundary::Clamp
+    });
+    registry.register(noise_settings.clone());
+    commands.remove_resource::<Biomes>();
+    tracker.max_stage = 1 + registry.names().count() as u32 + LATE_STAGES;
     tracker.add_progress(100.);
 }
 
-fn run_perlin_noise(
-    heightmap: ResMut<BitImage>,
+/// Runs the registered mutator called `name` through its mask, if it has one,
+/// one step per frame until its work is done.
+fn run_mutator(
+    name: &str,
+    mut heightmap: ResMut<BitImage>,
     mut rand: ResMut<RandStruct>,
+    registry: &mut MutatorRegistry,
+    falloff_mask: Option<&FalloffMask>,
     terrain_settings: Res<TerrainSettings>,
     mut tracker: ResMut<Tracker>,
 ) {
+    let work = match registry.work(name) {
+        Some(work) => work,
+        None => {
+            warn!("No height map mutator called {}", name);
+            tracker.add_progress(100.);
+            return;
+        }
+    };
     let s = terrain_settings.unit_count;
-    let rect = Rect {
-        top: 0,
-        left: 0,
-        bottom: s,
-        right: s,
+    let mut ctx = MutatorContext {
+        area: Rect {
+            top: 0,
+            left: 0,
+            bottom: s,
+            right: s,
+        },
+        rand: rand.as_mut(),
+        height_scale: terrain_settings.height_scale,
+        falloff_mask,
     };
-    let mut perlin = PerlinNoise::new(&mut rand);
-    perlin.run_mutate(heightmap, rect);
-    tracker.add_progress(100.);
+    if !tracker.is_working() {
+        if work == 0 {
+            tracker.add_progress(100.);
+            return;
+        }
+        registry.start(name, heightmap.as_ref(), &mut ctx);
+        tracker.start_work(work);
+    }
+    let done = registry.step(name, heightmap.as_mut(), &mut ctx, tracker.work_done());
+    tracker.add_work(done.max(1));
 }
 
-fn run_averaging(
-    mut heightmap: ResMut<BitImage>,
+/// Spawns the drops on the first frame, then waits for `ReverseRain`'s own
+/// systems to run them until every one of them has settled.
+fn run_reverse_rain(
+    mut commands: Commands,
+    mut rand: ResMut<RandStruct>,
+    rain_settings: Res<ReverseRainSettings>,
+    drops: Query<&ReverseRain>,
     terrain_settings: Res<TerrainSettings>,
     mut tracker: ResMut<Tracker>,
 ) {
@@ -102,25 +332,131 @@ fn run_averaging(
         bottom: s,
         right: s,
     };
-    let total = 5;
-    let step = 1. / total as f32;
-    average_by_neighbor(heightmap.as_mut(), rect);
-    tracker.add_progress(step);
+    if rain_settings.drop_count == 0 {
+        tracker.add_progress(100.);
+    } else if !tracker.is_working() {
+        for drop in rain_settings.spawn_drops(rand.as_mut(), rect) {
+            commands.spawn().insert(drop);
+        }
+        tracker.start_work(rain_settings.drop_count);
+    } else {
+        let settled = rain_settings.drop_count - drops.iter().count();
+        let done = tracker.work_done();
+        tracker.add_work(settled - done);
+    }
 }
 
-fn run_clean_edges(
-    mut heightmap: ResMut<BitImage>,
+/// Picks the water height that leaves `land_fraction` of the map above the
+/// sea. Rivers, lakes and everything after them use the new level.
+fn run_sea_level(
+    mut commands: Commands,
+    heightmap: ResMut<BitImage>,
     terrain_settings: Res<TerrainSettings>,
     mut tracker: ResMut<Tracker>,
 ) {
-    let s = terrain_settings.unit_count;
-    let rect = Rect {
-        top: 0,
-        left: 0,
-        bottom: s,
-        right: s,
+    let mut settings = TerrainSettings {
+        ..*terrain_settings
     };
-    zero_edges(heightmap.as_mut(), rect);
+    settings.calibrate_water(heightmap.as_ref());
+    commands.insert_resource(settings);
+    tracker.add_progress(100.);
+}
+
+fn run_rivers(
+    mut commands: Commands,
+    mut heightmap: ResMut<BitImage>,
+    mut rand: ResMut<RandStruct>,
+    river_settings: Res<RiverSettings>,
+    terrain_settings: Res<TerrainSettings>,
+    mut tracker: ResMut<Tracker>,
+) {
+    let water_level = terrain_settings.water_level();
+    let rivers = Rivers::trace(
+        heightmap.as_ref(),
+        rand.as_mut(),
+        river_settings.as_ref(),
+        water_level,
+    );
+    rivers.carve(heightmap.as_mut(), river_settings.as_ref());
+    commands.insert_resource(rivers);
+    tracker.add_progress(100.);
+}
+
+fn run_lakes(
+    mut commands: Commands,
+    heightmap: ResMut<BitImage>,
+    lake_settings: Res<LakeSettings>,
+    terrain_settings: Res<TerrainSettings>,
+    mut tracker: ResMut<Tracker>,
+) {
+    let water_level = terrain_settings.water_level();
+    let lakes = Lakes::find(heightmap.as_ref(), lake_settings.as_ref(), water_level);
+    commands.insert_resource(lakes);
+    tracker.add_progress(100.);
+}
+
+fn run_climate(
+    mut commands: Commands,
+    heightmap: ResMut<BitImage>,
+    climate_settings: Res<ClimateSettings>,
+    terrain_settings: Res<TerrainSettings>,
+    mut tracker: ResMut<Tracker>,
+) {
+    let climate = Climate::generate(
+        heightmap.as_ref(),
+        climate_settings.as_ref(),
+        terrain_settings.water_level(),
+        terrain_settings.height_scale,
+    );
+    commands.insert_resource(climate);
+    tracker.add_progress(100.);
+}
+
+fn run_biomes(
+    mut commands: Commands,
+    heightmap: ResMut<BitImage>,
+    climate: Res<Climate>,
+    biome_table: Res<BiomeTable>,
+    terrain_settings: Res<TerrainSettings>,
+    mut tracker: ResMut<Tracker>,
+) {
+    let biomes = Biomes::classify(
+        heightmap.as_ref(),
+        climate.as_ref(),
+        biome_table.as_ref(),
+        terrain_settings.water_level(),
+        terrain_settings.height_scale,
+    );
+    commands.insert_resource(biomes);
+    tracker.add_progress(100.);
+}
+
+fn run_feature_detection(
+    mut commands: Commands,
+    heightmap: ResMut<BitImage>,
+    mut tracker: ResMut<Tracker>,
+) {
+    let features = find_features(heightmap.as_ref(), &FeatureSettings::default());
+    commands.insert_resource(features);
+    tracker.add_progress(100.);
+}
+
+fn run_regions(
+    mut commands: Commands,
+    heightmap: ResMut<BitImage>,
+    mut rand: ResMut<RandStruct>,
+    region_settings: Res<RegionSettings>,
+    terrain_settings: Res<TerrainSettings>,
+    mut tracker: ResMut<Tracker>,
+) {
+    let water_level = terrain_settings.water_level();
+    let regions = Regions::generate(
+        heightmap.as_ref(),
+        rand.as_mut(),
+        region_settings.as_ref(),
+        water_level,
+    );
+    commands.insert_resource(regions);
     tracker.add_progress(100.);
 }
 
@@ -138,6 +474,32 @@ impl Tracker {
             self.current_stage += 1;
         }
     }
+
+    /// Starts counting units of work for a stage that is spread over several frames.
+    pub fn start_work(&mut self, total: usize) {
+        self.current_step_work = 0;
+        self.current_step_total = total;
+    }
+
+    pub fn is_working(&self) -> bool {
+        self.current_step_total > 0
+    }
+
+    pub fn work_done(&self) -> usize {
+        self.current_step_work
+    }
+
+    /// Records finished work, moving on to the next stage once all of it is done.
+    pub fn add_work(&mut self, done: usize) {
+        self.current_step_work += done;
+        if self.current_step_work >= self.current_step_total {
+            self.start_work(0);
+            self.add_progress(1.);
+        } else {
+            self.current_step_progress =
+                self.current_step_work as f32 / self.current_step_total as f32;
+        }
+    }
 }
 
 fn update_progress_bar(tracker: Res<Tracker>, mut query: Query<&mut Style, With<ProgressBar>>) {
@@ -154,18 +516,24 @@ fn update_image(
     image_handle: Res<ImageData>,
     mut images: ResMut<Assets<Image>>,
     height_map: Res<BitImage>,
+    biomes: Option<Res<Biomes>>,
     terrain_settings: Res<TerrainSettings>,
 ) {
     let image = images.get_mut(&image_handle.image_handle);
     if let Some(img) = image {
-        img.data.clone_from(
-            &height_map
-                .convert_to_rgba(terrain_settings.height_scale, terrain_settings.water_height),
-        );
+        let data = match biomes {
+            Some(biomes) => biomes.convert_to_rgba(height_map.as_ref()),
+            None => height_map.convert_to_rgba(
+                terrain_settings.world_scale(),
+                terrain_settings.water_height,
+            ),
+        };
+        img.data.clone_from(&data);
     }
 }
 
 fn reset_tracker(mut tracker: ResMut<Tracker>) {
     tracker.current_stage = 0;
     tracker.current_step_progress = 0.;
+    tracker.start_work(0);
 }
@@ -4,6 +4,7 @@ use bevy::{
     render::{options::WgpuOptions, render_resource::WgpuFeatures},
 };
 
+mod config;
 mod debug_camera;
 mod game;
 mod generation;
@@ -0,0 +1,320 @@
+use bevy::log::warn;
+
+use crate::{
+    config::{parse_numbers, Config},
+    map::{BitImage, Climate, MapLayer},
+};
+
+const ANY: [f32; 2] = [f32::NEG_INFINITY, f32::INFINITY];
+
+/// One row of the biome table. A cell belongs to the first biome whose
+/// ranges it falls in, each range taking its high end but not its low end,
+/// so a cell right at the water level is under the sea, as it is for the
+/// climate, rivers and lakes.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct Biome {
+    pub name: String,
+    pub color: [u8; 3],
+    /// In °C.
+    pub temperature: [f32; 2],
+    /// 0..1, as in `Climate::moisture`.
+    pub moisture: [f32; 2],
+    /// World units above the sea, 0 and below under it.
+    pub height: [f32; 2],
+    /// Steepest drop to a neighbor, in degrees.
+    pub slope: [f32; 2],
+}
+
+impl Biome {
+    /// Matches every cell until its ranges are narrowed down.
+    pub fn new(name: &str, color: [u8; 3]) -> Self {
+        Biome {
+            name: name.to_string(),
+            color,
+            temperature: ANY,
+            moisture: ANY,
+            height: ANY,
+            slope: ANY,
+        }
+    }
+
+    pub fn matches(&self, temperature: f32, moisture: f32, height: f32, slope: f32) -> bool {
+        let within = |[low, high]: [f32; 2], value: f32| value > low && value <= high;
+        within(self.temperature, temperature)
+            && within(self.moisture, moisture)
+            && within(self.height, height)
+            && within(self.slope, slope)
+    }
+
+    /// Reads a `biomes.cfg` entry, e.g. `40 130 60, moisture 0.35 inf`: the
+    /// color, then any number of `<range> <low> <high>`.
+    fn parse(name: &str, items: &[String]) -> Result<Self, String> {
+        let (color, ranges) = items
+            .split_first()
+            .ok_or(format!("{} has no color", name))?;
+        let color = parse_numbers(color).ok_or(format!("{}: bad color `{}`", name, color))?;
+        let mut biome = Biome::new(name, color);
+        for range in ranges {
+            let (key, values) = range.split_once(' ').unwrap_or((range, ""));
+            let values = parse_numbers(values).ok_or(format!("{}: bad range `{}`", name, range))?;
+            match key {
+                "temperature" => biome.temperature = values,
+                "moisture" => biome.moisture = values,
+                "height" => biome.height = values,
+                "slope" => biome.slope = values,
+                _ => return Err(format!("{}: unknown range `{}`", name, key)),
+            }
+        }
+        Ok(biome)
+    }
+}
+
+/// Biomes in the order they are checked. Loaded from
+/// `assets/config/biomes.cfg` at startup.
+pub struct BiomeTable {
+    pub biomes: Vec<Biome>,
+}
+
+impl Default for BiomeTable {
+    fn default() -> Self {
+        let biomes = vec![
+            Biome {
+                height: [f32::NEG_INFINITY, -40.],
+                ..Biome::new("deep_ocean", [20, 40, 120])
+            },
+            Biome {
+                height: [f32::NEG_INFINITY, 0.],
+                ..Biome::new("ocean", [40, 70, 160])
+            },
+            Biome {
+                height: [0., 2.],
+                slope: [f32::NEG_INFINITY, 15.],
+                ..Biome::new("beach", [210, 200, 150])
+            },
+            Biome {
+                temperature: [f32::NEG_INFINITY, -5.],
+                ..Biome::new("snow", [240, 240, 250])
+            },
+            Biome {
+                slope: [40., f32::INFINITY],
+                ..Biome::new("rock", [120, 115, 110])
+            },
+            Biome {
+                temperature: [f32::NEG_INFINITY, 2.],
+                ..Biome::new("tundra", [150, 160, 140])
+            },
+            Biome {
+                temperature: [f32::NEG_INFINITY, 8.],
+                moisture: [0.3, f32::INFINITY],
+                ..Biome::new("taiga", [60, 100, 80])
+            },
+            Biome {
+                moisture: [f32::NEG_INFINITY, 0.05],
+                ..Biome::new("desert", [220, 200, 140])
+            },
+            Biome {
+                temperature: [20., f32::INFINITY],
+                moisture: [f32::NEG_INFINITY, 0.35],
+                ..Biome::new("savanna", [170, 170, 90])
+            },
+            Biome {
+                temperature: [20., f32::INFINITY],
+                moisture: [0.6, f32::INFINITY],
+                ..Biome::new("tropical_rainforest", [30, 110, 50])
+            },
+            Biome {
+                moisture: [0.7, f32::INFINITY],
+                ..Biome::new("temperate_rainforest", [50, 120, 80])
+            },
+            Biome {
+                moisture: [0.35, f32::INFINITY],
+                ..Biome::new("forest", [70, 130, 60])
+            },
+            Biome::new("grassland", [120, 170, 80]),
+        ];
+        BiomeTable { biomes }
+    }
+}
+
+impl BiomeTable {
+    /// Reads the biomes listed under `order`. A table that fails to read is
+    /// reported and the built-in one is kept.
+    pub fn load() -> Self {
+        let mut table = BiomeTable::default();
+        if let Some(config) = Config::load("biomes") {
+            let mut order: Vec<String> = Vec::new();
+            config.read_list("order", &mut order, |name| Some(name.to_string()));
+            let biomes: Result<Vec<Biome>, String> = order
+                .iter()
+                .map(|name| {
+                    if config.get_str(name).is_none() {
+                        return Err(format!("no entry for {}", name));
+                    }
+                    let mut items: Vec<String> = Vec::new();
+                    config.read_list(name, &mut items, |item| Some(item.to_string()));
+                    Biome::parse(name, &items)
+                })
+                .collect();
+            match biomes {
+                Ok(biomes) if !biomes.is_empty() => table.biomes = biomes,
+                Ok(_) => (),
+                Err(e) => warn!("biomes.cfg: {}", e),
+            }
+        }
+        table
+    }
+
+    /// Index of the first matching biome, the last one when none match.
+    pub fn classify(&self, temperature: f32, moisture: f32, height: f32, slope: f32) -> usize {
+        self.biomes
+            .iter()
+            .position(|biome| biome.matches(temperature, moisture, height, slope))
+            .unwrap_or(self.biomes.len().saturating_sub(1))
+    }
+}
+
+/// Biome of every cell.
+#[allow(dead_code)]
+pub struct Biomes {
+    /// Index into `biomes` for every cell.
+    pub ids: MapLayer<u32>,
+    /// The table the map was classified with.
+    pub biomes: Vec<Biome>,
+}
+
+#[allow(dead_code)]
+impl Biomes {
+    pub fn classify(
+        height_map: &BitImage,
+        climate: &Climate,
+        table: &BiomeTable,
+        water_level: f32,
+        height_scale: f32,
+    ) -> Self {
+        let size = height_map.edge_size();
+        let mut ids = MapLayer::new(size, 0);
+        for y in 0..size {
+            for x in 0..size {
+                let temperature = *climate.temperature.get(x, y).unwrap_or(&0.);
+                let moisture = *climate.moisture.get(x, y).unwrap_or(&0.);
+                let height = (height_map.get_ignore(x, y) - water_level) * height_scale;
+                let slope = (height_map.slope(x, y) * height_scale).atan().to_degrees();
+                let id = table.classify(temperature, moisture, height, slope);
+                ids.set(x, y, id as u32);
+            }
+        }
+        Biomes {
+            ids,
+            biomes: table.biomes.clone(),
+        }
+    }
+
+    /// Color of the biome at a cell, black for cells off the map.
+    pub fn color(&self, x: usize, y: usize) -> [u8; 3] {
+        self.ids
+            .get(x, y)
+            .and_then(|id| self.biomes.get(*id as usize))
+            .map_or([0, 0, 0], |biome| biome.color)
+    }
+
+    /// Preview image in the layout of `BitImage::convert_to_rgba`, biome
+    /// colors darkened towards the low ground so the relief still shows.
+    pub fn convert_to_rgba(&self, height_map: &BitImage) -> Vec<u8> {
+        let size = height_map.edge_size();
+        let mut vec = vec![0; size * size * 4];
+        for (i, data) in height_map.get_heightmap_norm_iter().enumerate() {
+            let shade = 0.6 + 0.4 * data.clamp(0., 1.);
+            let color = self.color(i % size, i / size);
+            let idx = i * 4;
+            for c in 0..3 {
+                vec[idx + c] = (color[c] as f32 * shade) as u8;
+            }
+            vec[idx + 3] = 255;
+        }
+        vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(text: &str) -> Vec<String> {
+        text.split(',')
+            .map(|item| item.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parse_reads_the_color_and_ranges() {
+        let biome = Biome::parse(
+            "forest",
+            &items("70 130 60, moisture 0.35 inf, height -inf 10"),
+        )
+        .unwrap();
+        assert_eq!(biome.color, [70, 130, 60]);
+        assert_eq!(biome.moisture, [0.35, f32::INFINITY]);
+        assert_eq!(biome.height, [f32::NEG_INFINITY, 10.]);
+        assert_eq!(biome.temperature, ANY);
+        assert_eq!(biome.slope, ANY);
+    }
+
+    #[test]
+    fn parse_rejects_bad_entries() {
+        for bad in [
+            "",
+            "70 130",
+            "70 130 60, wetness 0 1",
+            "70 130 60, moisture 0.35",
+            "300 0 0",
+        ] {
+            assert!(Biome::parse("bad", &items(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn ranges_take_the_high_end_but_not_the_low_end() {
+        let biome = Biome {
+            height: [0., 2.],
+            ..Biome::new("beach", [0, 0, 0])
+        };
+        assert!(!biome.matches(0., 0., 0., 0.));
+        assert!(biome.matches(0., 0., 1., 0.));
+        assert!(biome.matches(0., 0., 2., 0.));
+    }
+
+    #[test]
+    fn default_table_puts_the_water_level_under_the_sea() {
+        let table = BiomeTable::default();
+        let name = |temperature, moisture, height, slope| {
+            table.biomes[table.classify(temperature, moisture, height, slope)]
+                .name
+                .as_str()
+        };
+        assert_eq!(name(15., 1., -100., 0.), "deep_ocean");
+        assert_eq!(name(15., 1., 0., 0.), "ocean");
+        assert_eq!(name(15., 0.5, 1., 0.), "beach");
+        assert_eq!(name(15., 0.5, 1., 30.), "forest");
+        assert_eq!(name(-10., 0.5, 50., 0.), "snow");
+        assert_eq!(name(25., 0.01, 50., 0.), "desert");
+        assert_eq!(name(12., 0.2, 50., 0.), "grassland");
+    }
+
+    #[test]
+    fn classify_falls_back_to_the_last_biome() {
+        let table = BiomeTable {
+            biomes: vec![
+                Biome {
+                    height: [f32::NEG_INFINITY, 0.],
+                    ..Biome::new("sea", [0, 0, 255])
+                },
+                Biome {
+                    height: [0., 1.],
+                    ..Biome::new("shore", [255, 255, 0])
+                },
+            ],
+        };
+        assert_eq!(table.classify(0., 0., 100., 0.), 1);
+    }
+}
@@ -0,0 +1,340 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use noise::{NoiseFn, Perlin, Seedable};
+
+use crate::map::{BitImage, GrayImage};
+
+/// How a brush fades from its center to its rim.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrushFalloff {
+    /// Full strength all the way to the rim, mostly for stamps.
+    Constant,
+    Linear,
+    Smoothstep,
+    Gaussian,
+}
+
+impl BrushFalloff {
+    pub const ALL: [BrushFalloff; 4] = [
+        BrushFalloff::Constant,
+        BrushFalloff::Linear,
+        BrushFalloff::Smoothstep,
+        BrushFalloff::Gaussian,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BrushFalloff::Constant => "constant",
+            BrushFalloff::Linear => "linear",
+            BrushFalloff::Smoothstep => "smoothstep",
+            BrushFalloff::Gaussian => "gaussian",
+        }
+    }
+
+    /// Weight at `t`, the distance from the center over the radius. 1 at the
+    /// center, 0 at the rim and beyond.
+    pub fn weight(&self, t: f32) -> f32 {
+        if t >= 1. {
+            return 0.;
+        }
+        let t = t.max(0.);
+        match self {
+            BrushFalloff::Constant => 1.,
+            BrushFalloff::Linear => 1. - t,
+            BrushFalloff::Smoothstep => 1. - t * t * (3. - 2. * t),
+            BrushFalloff::Gaussian => {
+                // shifted and scaled so it still reaches 0 at the rim
+                let rim = (-4f32).exp();
+                ((-4. * t * t).exp() - rim) / (1. - rim)
+            }
+        }
+    }
+}
+
+impl FromStr for BrushFalloff {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BrushFalloff::ALL
+            .into_iter()
+            .find(|falloff| falloff.name() == s)
+            .ok_or(format!("Unknown falloff curve: {}", s))
+    }
+}
+
+/// Grayscale height profile pressed into the ground by a stamp brush,
+/// stretched over the brush's square.
+#[derive(Clone)]
+pub struct Stamp(pub GrayImage);
+
+impl Stamp {
+    /// Bowl with a raised rim, below zero in the middle.
+    pub fn crater(size: usize) -> Self {
+        Stamp(GrayImage::from_fn(size, |u, v| {
+            let r = (u * u + v * v).sqrt();
+            if r < 0.7 {
+                (r / 0.7).powi(2) * 1.3 - 1.
+            } else {
+                0.3 * (1. - (r - 0.7) / 0.3).max(0.)
+            }
+        }))
+    }
+
+    /// Cone with a small caldera at the top.
+    pub fn volcano(size: usize) -> Self {
+        Stamp(GrayImage::from_fn(size, |u, v| {
+            let r = (u * u + v * v).sqrt();
+            if r < 0.15 {
+                0.8 + r
+            } else {
+                (1. - (r - 0.15) / 0.85).max(0.).powf(1.5)
+            }
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub enum BrushKind {
+    Raise,
+    Lower,
+    /// Pulls the ground towards this height.
+    Flatten(f32),
+    /// Pulls every cell towards the average of its neighbors.
+    Smooth,
+    /// Adds Perlin noise with this seed and frequency, in bumps per cell.
+    Noise {
+        seed: u32,
+        frequency: f64,
+    },
+    Stamp(Stamp),
+}
+
+/// Local edit of the height map, centered anywhere between cells.
+#[derive(Clone)]
+pub struct Brush {
+    pub kind: BrushKind,
+    /// In cells.
+    pub radius: f32,
+    /// Height added at the center for raise, lower, noise and stamps. For
+    /// flatten and smooth, the share of the way to the target, 0..1.
+    pub strength: f32,
+    pub falloff: BrushFalloff,
+}
+
+impl Brush {
+    pub fn new(kind: BrushKind, radius: f32, strength: f32, falloff: BrushFalloff) -> Self {
+        Brush {
+            kind,
+            radius,
+            strength,
+            falloff,
+        }
+    }
+
+    /// Applies the brush once around `center`, in cell coordinates.
+    pub fn apply(&self, height_map: &mut BitImage, center: Vec2) {
+        let size = height_map.edge_size() as isize;
+        let reach = self.radius.ceil() as isize;
+        let (cx, cy) = (center.x.round() as isize, center.y.round() as isize);
+        let left = (cx - reach).max(0);
+        let top = (cy - reach).max(0);
+        let right = (cx + reach).min(size - 1);
+        let bottom = (cy + reach).min(size - 1);
+        if left > right || top > bottom {
+            return;
+        }
+
+        // smoothing reads the neighbors as they were before this stroke
+        let before = match self.kind {
+            BrushKind::Smooth => Some(averages(height_map, left, top, right, bottom)),
+            _ => None,
+        };
+        let noise = match self.kind {
+            BrushKind::Noise { seed, .. } => Some(Perlin::new().set_seed(seed)),
+            _ => None,
+        };
+
+        let width = (right - left + 1) as usize;
+        for y in top..=bottom {
+            for x in left..=right {
+                let offset = Vec2::new(x as f32, y as f32) - center;
+                let weight = if self.radius > 0. {
+                    self.falloff.weight(offset.length() / self.radius)
+                } else {
+                    0.
+                };
+                if weight <= 0. {
+                    continue;
+                }
+                let (ux, uy) = (x as usize, y as usize);
+                let h = height_map.get_ignore(ux, uy);
+                let pull = (self.strength * weight).clamp(0., 1.);
+                let new = match &self.kind {
+                    BrushKind::Raise => h + self.strength * weight,
+                    BrushKind::Lower => h - self.strength * weight,
+                    BrushKind::Flatten(level) => h + (level - h) * pull,
+                    BrushKind::Smooth => {
+                        let averages = before.as_ref().unwrap();
+                        let i = (y - top) as usize * width + (x - left) as usize;
+                        h + (averages[i] - h) * pull
+                    }
+                    BrushKind::Noise { frequency, .. } => {
+                        let point = [x as f64 * frequency, y as f64 * frequency];
+                        let n = noise.as_ref().unwrap().get(point) as f32;
+                        h + self.strength * weight * n
+                    }
+                    BrushKind::Stamp(stamp) => {
+                        let u = offset.x / (2. * self.radius) + 0.5;
+                        let v = offset.y / (2. * self.radius) + 0.5;
+                        h + self.strength * weight * stamp.0.sample(u, v)
+                    }
+                };
+                height_map.point_set(ux, uy, new);
+            }
+        }
+    }
+}
+
+/// A brush placed on the map, its center and radius given as shares of the
+/// map width so the same strokes fit any map size.
+#[derive(Clone)]
+pub struct BrushStroke {
+    pub brush: Brush,
+    /// 0..1 across the map.
+    pub center: Vec2,
+}
+
+impl BrushStroke {
+    pub fn apply(&self, height_map: &mut BitImage) {
+        let size = (height_map.edge_size() - 1) as f32;
+        let brush = Brush {
+            radius: self.brush.radius * size,
+            ..self.brush.clone()
+        };
+        brush.apply(height_map, self.center * size);
+    }
+}
+
+/// Parses `<kind> <x> <y> <radius> <strength> <falloff>`, followed by the
+/// height for `flatten` and the frequency for `noise`. Kinds are raise,
+/// lower, flatten, smooth, noise, crater and volcano. Noise strokes use a
+/// fixed seed, so they come out the same on every map.
+impl FromStr for BrushStroke {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let (kind, numbers, falloff, extra) = match parts.as_slice() {
+            [kind, x, y, radius, strength, falloff, extra @ ..] if extra.len() <= 1 => {
+                (*kind, [*x, *y, *radius, *strength], *falloff, extra.first())
+            }
+            _ => {
+                return Err(format!(
+                    "Expected `<kind> <x> <y> <radius> <strength> <falloff>`, got `{}`",
+                    s
+                ))
+            }
+        };
+        let number = |n: &str| -> Result<f32, String> {
+            n.parse()
+                .map_err(|_| format!("Bad number `{}` in `{}`", n, s))
+        };
+        let [x, y, radius, strength] = [
+            number(numbers[0])?,
+            number(numbers[1])?,
+            number(numbers[2])?,
+            number(numbers[3])?,
+        ];
+        let extra = match (kind, extra) {
+            ("flatten" | "noise", Some(n)) => number(n)?,
+            ("flatten" | "noise", None) => return Err(format!("{} needs a value: `{}`", kind, s)),
+            (_, None) => 0.,
+            (_, Some(_)) => return Err(format!("{} takes no value: `{}`", kind, s)),
+        };
+        let kind = match kind {
+            "raise" => BrushKind::Raise,
+            "lower" => BrushKind::Lower,
+            "flatten" => BrushKind::Flatten(extra),
+            "smooth" => BrushKind::Smooth,
+            "noise" => BrushKind::Noise {
+                seed: 0,
+                frequency: extra as f64,
+            },
+            "crater" => BrushKind::Stamp(Stamp::crater(64)),
+            "volcano" => BrushKind::Stamp(Stamp::volcano(64)),
+            _ => return Err(format!("Unknown brush: {}", kind)),
+        };
+        Ok(BrushStroke {
+            brush: Brush::new(kind, radius, strength, falloff.parse()?),
+            center: Vec2::new(x, y),
+        })
+    }
+}
+
+/// Mean of each cell's in-bounds neighbors over the box, in row order.
+fn averages(
+    height_map: &BitImage,
+    left: isize,
+    top: isize,
+    right: isize,
+    bottom: isize,
+) -> Vec<f32> {
+    let mut out = Vec::with_capacity(((right - left + 1) * (bottom - top + 1)) as usize);
+    for y in top..=bottom {
+        for x in left..=right {
+            let (count, sum) = height_map.reduce_neighbors(
+                x as usize,
+                y as usize,
+                (0, 0.),
+                |(count, sum), val: f32| (count + 1, sum + val),
+            );
+            out.push(sum / count.max(1) as f32);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falloff_runs_from_full_at_the_center_to_none_at_the_rim() {
+        for falloff in BrushFalloff::ALL {
+            assert_eq!(falloff.weight(0.), 1., "{}", falloff.name());
+            assert_eq!(falloff.weight(1.), 0., "{}", falloff.name());
+            assert_eq!(falloff.weight(1.5), 0., "{}", falloff.name());
+            assert_eq!(falloff.weight(-0.5), 1., "{}", falloff.name());
+        }
+    }
+
+    #[test]
+    fn falloff_only_drops_towards_the_rim() {
+        for falloff in BrushFalloff::ALL {
+            let weights: Vec<f32> = (0..=20).map(|i| falloff.weight(i as f32 / 20.)).collect();
+            assert!(
+                weights.windows(2).all(|pair| pair[1] <= pair[0]),
+                "{}",
+                falloff.name()
+            );
+        }
+    }
+
+    #[test]
+    fn falloff_midpoints() {
+        assert_eq!(BrushFalloff::Constant.weight(0.5), 1.);
+        assert_eq!(BrushFalloff::Linear.weight(0.25), 0.75);
+        assert_eq!(BrushFalloff::Smoothstep.weight(0.5), 0.5);
+        let gaussian = BrushFalloff::Gaussian.weight(0.5);
+        assert!((gaussian - 0.3561).abs() < 1e-3, "{}", gaussian);
+    }
+
+    #[test]
+    fn falloff_names_parse_back() {
+        for falloff in BrushFalloff::ALL {
+            assert_eq!(falloff.name().parse(), Ok(falloff));
+        }
+        assert!("cubic".parse::<BrushFalloff>().is_err());
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+
+use crate::{
+    config::Config,
+    map::{BitImage, Boundary, MapLayer},
+};
+
+/// Temperature and rainfall over the finished height map. Loaded from
+/// `assets/config/climate.cfg` at startup.
+pub struct ClimateSettings {
+    /// Latitude of the top and bottom rows of the map, in degrees.
+    pub north_latitude: f32,
+    pub south_latitude: f32,
+    /// Sea level temperature at the equator and at the poles, in °C.
+    pub equator_temperature: f32,
+    pub pole_temperature: f32,
+    /// Temperature drop per world unit of height above the sea, in °C.
+    pub lapse_rate: f32,
+    /// Temperature the sea pulls the coasts towards, in °C. Inland cells
+    /// drift away from it, colder where it is cold and hotter where it is hot.
+    pub ocean_temperature: f32,
+    /// How far inland cells drift from `ocean_temperature`, 0..1 of the gap.
+    pub continentality: f32,
+    /// Distance from the sea, in cells, over which the drift builds up.
+    pub ocean_reach: f32,
+    /// Direction the prevailing wind blows towards, in degrees. 0 runs along
+    /// +x, 90 along +y.
+    pub wind_angle: f32,
+    /// Share of the missing humidity the air takes up over each sea cell.
+    pub evaporation: f32,
+    /// Share of its humidity the air rains out over each flat land cell.
+    pub rain_rate: f32,
+    /// Extra share rained out per world unit the ground rises under the air,
+    /// so the windward slopes of mountains get the rain and the lee side stays dry.
+    pub orographic_rain: f32,
+}
+
+impl Default for ClimateSettings {
+    fn default() -> Self {
+        ClimateSettings {
+            north_latitude: 60.,
+            south_latitude: 20.,
+            equator_temperature: 30.,
+            pole_temperature: -20.,
+            lapse_rate: 0.1,
+            ocean_temperature: 15.,
+            continentality: 0.3,
+            ocean_reach: 64.,
+            wind_angle: 0.,
+            evaporation: 0.05,
+            rain_rate: 0.004,
+            orographic_rain: 0.05,
+        }
+    }
+}
+
+impl ClimateSettings {
+    pub fn load() -> Self {
+        let mut settings = ClimateSettings::default();
+        if let Some(config) = Config::load("climate") {
+            config.read("north_latitude", &mut settings.north_latitude);
+            config.read("south_latitude", &mut settings.south_latitude);
+            config.read("equator_temperature", &mut settings.equator_temperature);
+            config.read("pole_temperature", &mut settings.pole_temperature);
+            config.read("lapse_rate", &mut settings.lapse_rate);
+            config.read("ocean_temperature", &mut settings.ocean_temperature);
+            config.read("continentality", &mut settings.continentality);
+            config.read("ocean_reach", &mut settings.ocean_reach);
+            config.read("wind_angle", &mut settings.wind_angle);
+            config.read("evaporation", &mut settings.evaporation);
+            config.read("rain_rate", &mut settings.rain_rate);
+            config.read("orographic_rain", &mut settings.orographic_rain);
+        }
+        settings
+    }
+}
+
+/// Climate layers, one value per height map cell.
+#[allow(dead_code)]
+pub struct Climate {
+    /// Mean temperature in °C.
+    pub temperature: MapLayer<f32>,
+    /// Rainfall, 0..1. Flat coastal land facing the wind gets about 0.6,
+    /// windward slopes more, dry interiors and rain shadows close to 0.
+    /// The sea is 1.
+    pub moisture: MapLayer<f32>,
+    /// Steps to the nearest sea cell, 0 for the sea itself.
+    pub ocean_distance: MapLayer<u32>,
+}
+
+#[allow(dead_code)]
+impl Climate {
+    pub fn generate(
+        height_map: &BitImage,
+        settings: &ClimateSettings,
+        water_level: f32,
+        height_scale: f32,
+    ) -> Self {
+        let ocean_distance = ocean_distance(height_map, water_level);
+        let temperature = temperature(
+            height_map,
+            settings,
+            &ocean_distance,
+            water_level,
+            height_scale,
+        );
+        let moisture = moisture(height_map, settings, water_level, height_scale);
+        Climate {
+            temperature,
+            moisture,
+            ocean_distance,
+        }
+    }
+}
+
+/// Grows out from every cell at or below `water_level`, one neighbor step at
+/// a time. A map without sea is `u32::MAX` everywhere.
+fn ocean_distance(height_map: &BitImage, water_level: f32) -> MapLayer<u32> {
+    let size = height_map.edge_size();
+    let mut distance = MapLayer::new(size, u32::MAX);
+    let mut queue = VecDeque::new();
+    for y in 0..size {
+        for x in 0..size {
+            if height_map.get_ignore(x, y) <= water_level {
+                distance.set(x, y, 0);
+                queue.push_back((x, y));
+            }
+        }
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        let next = distance.get(x, y).copied().unwrap_or_default() + 1;
+        for (nx, ny) in height_map.neighbors(x, y) {
+            if distance.get(nx, ny) == Some(&u32::MAX) {
+                distance.set(nx, ny, next);
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    distance
+}
+
+/// Latitude sets the sea level temperature, the sea evens it out near the
+/// coasts, and it drops with height above the sea.
+fn temperature(
+    height_map: &BitImage,
+    settings: &ClimateSettings,
+    ocean_distance: &MapLayer<u32>,
+    water_level: f32,
+    height_scale: f32,
+) -> MapLayer<f32> {
+    let size = height_map.edge_size();
+    let mut temperature = MapLayer::new(size, 0.);
+    let last = (size - 1).max(1) as f32;
+    for y in 0..size {
+        let f = y as f32 / last;
+        let latitude =
+            settings.north_latitude + (settings.south_latitude - settings.north_latitude) * f;
+        let sea = settings.pole_temperature
+            + (settings.equator_temperature - settings.pole_temperature)
+                * latitude.to_radians().cos().max(0.);
+        for x in 0..size {
+            let distance = *ocean_distance.get(x, y).unwrap_or(&u32::MAX) as f32;
+            let inland = if settings.ocean_reach > 0. {
+                1. - (-distance / settings.ocean_reach).exp()
+            } else {
+                1.
+            };
+            let drift = (sea - settings.ocean_temperature) * settings.continentality * inland;
+            let altitude = (height_map.get_ignore(x, y) - water_level).max(0.) * height_scale;
+            temperature.set(x, y, sea + drift - settings.lapse_rate * altitude);
+        }
+    }
+    temperature
+}
+
+/// Carries humidity across the map with the wind, sweeping from the upwind
+/// corner so every cell's upwind neighbors are done before it. The air takes
+/// up water over the sea and rains it out over land, faster where the ground
+/// climbs. Air blowing in from past the edge is saturated; a wrapping map is
+/// swept twice so the air crossing its edge comes from the far side.
+fn moisture(
+    height_map: &BitImage,
+    settings: &ClimateSettings,
+    water_level: f32,
+    height_scale: f32,
+) -> MapLayer<f32> {
+    let size = height_map.edge_size();
+    let angle = settings.wind_angle.to_radians();
+    let (wx, wy) = (angle.cos(), angle.sin());
+    let (ax, ay) = (wx.abs(), wy.abs());
+    // upwind share coming from the x and from the y neighbor
+    let (fx, fy) = (ax / (ax + ay), ay / (ax + ay));
+    let (sx, sy) = (-(wx.signum() as isize), -(wy.signum() as isize));
+    let order = |forward: bool| -> Vec<usize> {
+        if forward {
+            (0..size).collect()
+        } else {
+            (0..size).rev().collect()
+        }
+    };
+    let (xs, ys) = (order(wx >= 0.), order(wy >= 0.));
+    let ground = |x: usize, y: usize| height_map.get_ignore(x, y).max(water_level);
+
+    let mut humidity = vec![1f32; size * size];
+    let mut rain = vec![0f32; size * size];
+    let passes = if height_map.boundary() == Boundary::Wrap {
+        2
+    } else {
+        1
+    };
+    for _ in 0..passes {
+        for &y in ys.iter() {
+            for &x in xs.iter() {
+                let g = ground(x, y);
+                let (mut air, mut below) = (0., 0.);
+                for (share, dx, dy) in [(fx, sx, 0), (fy, 0, sy)] {
+                    if share <= 0. {
+                        continue;
+                    }
+                    let upwind = height_map.wrap_coords(x as isize + dx, y as isize + dy);
+                    let (h, u) = match upwind {
+                        Some((ux, uy)) => (humidity[uy * size + ux], ground(ux, uy)),
+                        None => (1., g),
+                    };
+                    air += share * h;
+                    below += share * u;
+                }
+                let cell = y * size + x;
+                if height_map.get_ignore(x, y) <= water_level {
+                    humidity[cell] = air + (1. - air) * settings.evaporation;
+                    rain[cell] = f32::INFINITY;
+                } else {
+                    let rise = (g - below).max(0.) * height_scale;
+                    let share = (settings.rain_rate + settings.orographic_rain * rise).min(1.);
+                    rain[cell] = air * share;
+                    humidity[cell] = air - rain[cell];
+                }
+            }
+        }
+    }
+
+    let mut moisture = MapLayer::new(size, 0.);
+    let scale = settings.rain_rate.max(f32::EPSILON);
+    for y in 0..size {
+        for x in 0..size {
+            moisture.set(x, y, 1. - (-rain[y * size + x] / scale).exp());
+        }
+    }
+    moisture
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 33 by 33 map with sea at 0 in the four columns on the left, land at
+    /// 0.3 and a ridge at 1 down column 16.
+    fn ridge() -> BitImage {
+        let mut height_map = BitImage::new(32);
+        for y in 0..33 {
+            for x in 0..33 {
+                let h = match x {
+                    0..=3 => 0.,
+                    16 => 1.,
+                    _ => 0.3,
+                };
+                height_map.point_set(x, y, h);
+            }
+        }
+        height_map
+    }
+
+    fn climate(height_map: &BitImage, settings: &ClimateSettings) -> Climate {
+        Climate::generate(height_map, settings, 0.1, 10.)
+    }
+
+    #[test]
+    fn ocean_distance_counts_steps_from_the_sea() {
+        let climate = climate(&ridge(), &ClimateSettings::default());
+        for x in [0, 3, 4, 10, 32] {
+            assert_eq!(
+                climate.ocean_distance.get(x, 8),
+                Some(&(x.max(3) as u32 - 3))
+            );
+        }
+        let dry = BitImage::new(8);
+        assert!(ocean_distance(&dry, -1.)
+            .iter()
+            .all(|(_, d)| *d == u32::MAX));
+    }
+
+    #[test]
+    fn moisture_drops_downwind_of_a_ridge() {
+        let climate = climate(&ridge(), &ClimateSettings::default());
+        let moisture = |x| *climate.moisture.get(x, 16).unwrap();
+        assert_eq!(moisture(2), 1.);
+        assert!(moisture(16) > moisture(12));
+        assert!(moisture(20) < moisture(12) * 0.8);
+        assert!(moisture(5) > moisture(12));
+    }
+
+    #[test]
+    fn wind_direction_picks_the_wet_side() {
+        let settings = ClimateSettings {
+            wind_angle: 180.,
+            ..ClimateSettings::default()
+        };
+        let climate = climate(&ridge(), &settings);
+        let moisture = |x| *climate.moisture.get(x, 16).unwrap();
+        // air comes in saturated from past the right edge
+        assert!(moisture(20) > moisture(12));
+    }
+
+    #[test]
+    fn temperature_drops_with_height_and_latitude() {
+        let settings = ClimateSettings {
+            continentality: 0.,
+            ..ClimateSettings::default()
+        };
+        let climate = climate(&ridge(), &settings);
+        let temperature = |x, y| *climate.temperature.get(x, y).unwrap();
+        assert!(temperature(10, 0) < temperature(10, 32));
+        let drop = temperature(10, 8) - temperature(16, 8);
+        assert!((drop - 0.1 * 0.7 * 10.).abs() < 1e-4);
+        let pole = 30. - 50. * (1. - 60f32.to_radians().cos());
+        assert!((temperature(0, 0) - pole).abs() < 1e-4);
+    }
+
+    #[test]
+    fn inland_drifts_away_from_the_sea_temperature() {
+        let settings = ClimateSettings {
+            lapse_rate: 0.,
+            ..ClimateSettings::default()
+        };
+        let climate = climate(&ridge(), &settings);
+        let temperature = |x| *climate.temperature.get(x, 32).unwrap();
+        // the south row is warmer than the sea, so inland gets hotter still,
+        // and the north row is colder, so inland gets colder
+        assert!(temperature(30) > temperature(5));
+        let temperature = |x| *climate.temperature.get(x, 0).unwrap();
+        assert!(temperature(30) < temperature(5));
+    }
+}
@@ -17,27 +17,104 @@ fn init_bit_world_map(mut commands: Commands, terrain_settings: Res<TerrainSetti
     commands.insert_resource(BitImage::new(terrain_settings.unit_count));
 }
 
+const NEIGHBOR_OFFSETS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// What neighbor lookups do at the edge of the map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    /// Cells past the edge don't exist.
+    Clamp,
+    /// The map repeats, the last row and column are the same cells as the
+    /// first ones, so tiles placed side by side join without a seam.
+    Wrap,
+}
+
+#[derive(Clone)]
 pub struct BitImage {
     data: Vec<f32>,
     edge_size: usize,
     max_height: f32,
     min_height: f32,
+    boundary: Boundary,
 }
 
 #[allow(dead_code)]
 impl BitImage {
-    fn new(edge_size: usize) -> Self {
+    pub fn new(edge_size: usize) -> Self {
         let len = edge_size + 1;
         BitImage {
             data: vec![0.; len * len],
             edge_size: len,
             max_height: 0.,
             min_height: 0.,
+            boundary: Boundary::Clamp,
+        }
+    }
+
+    pub fn boundary(&self) -> Boundary {
+        self.boundary
+    }
+
+    pub fn set_boundary(&mut self, boundary: Boundary) {
+        self.boundary = boundary;
+    }
+
+    /// Brings a cell position that may be off the map back onto it. Positions
+    /// on the map are returned as they are, off the map they wrap around with
+    /// `Boundary::Wrap` and give `None` with `Boundary::Clamp`.
+    pub fn wrap_coords(&self, x: isize, y: isize) -> Option<(usize, usize)> {
+        let size = self.edge_size as isize;
+        let wrap = |v: isize| {
+            if v >= 0 && v < size {
+                Some(v as usize)
+            } else if self.boundary == Boundary::Wrap && size > 1 {
+                Some(v.rem_euclid(size - 1) as usize)
+            } else {
+                None
+            }
+        };
+        Some((wrap(x)?, wrap(y)?))
+    }
+
+    /// Copies the first row and column over the last ones on a wrapping map,
+    /// stages that move material around can leave the two slightly apart.
+    pub fn match_edges(&mut self) {
+        if self.boundary != Boundary::Wrap {
+            return;
+        }
+        let last = self.edge_size - 1;
+        for i in 0..self.edge_size {
+            self.data[i * self.edge_size + last] = self.data[i * self.edge_size];
+            self.data[last * self.edge_size + i] = self.data[i];
         }
     }
 
     pub fn clear(&mut self) {
         self.data.fill(0.);
+        self.max_height = 0.;
+        self.min_height = 0.;
+    }
+
+    /// Narrows `min_height` and `max_height` back down to the heights on the
+    /// map. Setting cells only ever widens them.
+    pub fn recompute_bounds(&mut self) {
+        let (min, max) = self
+            .data
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), h| {
+                (min.min(*h), max.max(*h))
+            });
+        self.min_height = min;
+        self.max_height = max;
     }
 
     pub fn get(&self, x: usize, y: usize) -> Result<f32, String> {
@@ -49,6 +126,26 @@ impl BitImage {
         self.get(x, y).unwrap_or_default()
     }
 
+    pub fn edge_size(&self) -> usize {
+        self.edge_size
+    }
+
+    pub fn max_height(&self) -> f32 {
+        self.max_height
+    }
+
+    pub fn min_height(&self) -> f32 {
+        self.min_height
+    }
+
+    /// Height with `fraction`, 0..1, of the cells at or below it.
+    pub fn height_quantile(&self, fraction: f32) -> f32 {
+        let mut heights = self.data.clone();
+        let last = heights.len() - 1;
+        let index = (fraction.clamp(0., 1.) * last as f32).round() as usize;
+        *heights.select_nth_unstable_by(index, f32::total_cmp).1
+    }
+
     pub fn get_normalized(&self, x: usize, y: usize) -> Result<f32, String> {
         self.check_coords(x, y)?;
         Ok((self.data[y * self.edge_size + x] - self.min_height)
@@ -95,7 +192,7 @@ impl BitImage {
     }
 
     pub fn neighbor_raise(&mut self, x: usize, y: usize, val: f32) {
-        let neighbors = Self::get_neighbors(x, y);
+        let neighbors = self.neighbor_coords(x, y);
         for coord in neighbors {
             self.point_raise(coord.0, coord.1, val);
         }
@@ -105,7 +202,7 @@ impl BitImage {
     where
         F: Fn(&f32, &f32) -> bool,
     {
-        let neighbors = Self::get_neighbors(x, y);
+        let neighbors = self.neighbor_coords(x, y);
         let mut max = self.get(x, y).unwrap();
         let mut max_coord = None;
         for coord in neighbors {
@@ -122,7 +219,7 @@ impl BitImage {
     where
         F: Fn(T, f32) -> T,
     {
-        let neighbors = Self::get_neighbors(x, y);
+        let neighbors = self.neighbor_coords(x, y);
         for coord in neighbors {
             if let Ok(current) = self.get(coord.0, coord.1) {
                 start = reducer(start, current);
@@ -131,6 +228,43 @@ impl BitImage {
         start
     }
 
+    /// Steepest height difference between a cell and its neighbors, per cell
+    /// of distance: diagonal differences are divided by the square root of 2,
+    /// as `ThermalErosion` widens its talus limit for them.
+    pub fn slope(&self, x: usize, y: usize) -> f32 {
+        let h = self.get_ignore(x, y);
+        NEIGHBOR_OFFSETS
+            .iter()
+            .filter_map(|(dx, dy)| {
+                let (nx, ny) = self.wrap_coords(x as isize + dx, y as isize + dy)?;
+                let drop = (h - self.get_ignore(nx, ny)).abs();
+                Some(if *dx != 0 && *dy != 0 {
+                    drop / std::f32::consts::SQRT_2
+                } else {
+                    drop
+                })
+            })
+            .fold(0., f32::max)
+    }
+
+    /// Iterates the 8-connected neighbors of a cell, wrapped around the edges
+    /// with `Boundary::Wrap` and left out past them with `Boundary::Clamp`.
+    pub fn neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        NEIGHBOR_OFFSETS
+            .iter()
+            .filter_map(move |(dx, dy)| self.wrap_coords(x as isize + dx, y as isize + dy))
+    }
+
+    fn neighbor_coords(&self, x: usize, y: usize) -> [(usize, usize); 8] {
+        match self.boundary {
+            Boundary::Clamp => Self::get_neighbors(x, y),
+            Boundary::Wrap => NEIGHBOR_OFFSETS.map(|(dx, dy)| {
+                self.wrap_coords(x as isize + dx, y as isize + dy)
+                    .unwrap_or((x, y))
+            }),
+        }
+    }
+
     fn get_neighbors(x: usize, y: usize) -> [(usize, usize); 8] {
         let y0 = if y > 0 { y - 1 } else { y };
         let x0 = if x > 0 { x - 1 } else { x };
@@ -186,3 +320,76 @@ impl BitImage {
         vec
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slope_spreads_diagonal_drops_over_their_length() {
+        let mut image = BitImage::new(4);
+        for (x, y) in [(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (0, 2)] {
+            image.point_set(x, y, 1.);
+        }
+        image.point_set(2, 1, 0.5);
+        image.point_set(1, 2, 0.5);
+        image.point_set(2, 2, 1. - std::f32::consts::SQRT_2);
+        // the diagonal drop of 1.41 counts as 1, the straight ones are 0.5
+        assert!(
+            (image.slope(1, 1) - 1.).abs() < 1e-5,
+            "{}",
+            image.slope(1, 1)
+        );
+    }
+
+    #[test]
+    fn slope_wraps_around_on_a_tileable_map() {
+        let mut image = BitImage::new(4);
+        image.set_boundary(Boundary::Wrap);
+        image.point_set(3, 0, 0.5);
+        assert_eq!(image.slope(0, 0), 0.5);
+        image.set_boundary(Boundary::Clamp);
+        assert_eq!(image.slope(0, 0), 0.);
+    }
+
+    /// 5 by 5 cells with heights 0..25 in row order.
+    fn ramp() -> BitImage {
+        let mut image = BitImage::new(4);
+        for y in 0..5 {
+            for x in 0..5 {
+                image.point_set(x, y, (y * 5 + x) as f32);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn height_quantile_leaves_the_fraction_at_or_below() {
+        let image = ramp();
+        assert_eq!(image.height_quantile(0.), 0.);
+        assert_eq!(image.height_quantile(1.), 24.);
+        assert_eq!(image.height_quantile(0.5), 12.);
+        let level = image.height_quantile(0.3);
+        let below = image.get_heightmap_iter().filter(|h| *h <= level).count();
+        assert_eq!(below as f32 / 25., 0.32);
+    }
+
+    #[test]
+    fn height_quantile_clamps_the_fraction() {
+        let image = ramp();
+        assert_eq!(image.height_quantile(-1.), 0.);
+        assert_eq!(image.height_quantile(2.), 24.);
+    }
+
+    #[test]
+    fn bounds_narrow_on_recompute_and_reset_on_clear() {
+        let mut image = ramp();
+        image.point_set(0, 0, -5.);
+        image.point_set(0, 0, 0.);
+        assert_eq!(image.min_height(), -5.);
+        image.recompute_bounds();
+        assert_eq!((image.min_height(), image.max_height()), (0., 24.));
+        image.clear();
+        assert_eq!((image.min_height(), image.max_height()), (0., 0.));
+    }
+}
@@ -0,0 +1,261 @@
+use bevy::prelude::*;
+use noise::NoiseFn;
+
+use crate::{
+    map::{BitImage, DomainWarp, FalloffMask, NoiseSettings},
+    randstruct::RandStruct,
+};
+
+/// Midpoint displacement on a `2^n + 1` grid. Every level fills in the centers
+/// of the squares (diamond step), then the centers of their edges (square
+/// step), each with a random offset that shrinks by `roughness` per level.
+pub struct DiamondSquare {
+    grid: Vec<f64>,
+    size: usize,
+    wrap: bool,
+}
+
+impl DiamondSquare {
+    /// Builds a grid with at least `min_size` points per side, scaled to 0..1.
+    /// A `wrap` grid repeats, its last row and column are copies of the first
+    /// ones and the square step reaches across the edges.
+    pub fn new(rand: &mut RandStruct, roughness: f64, min_size: usize, wrap: bool) -> Self {
+        let size = min_size.saturating_sub(1).next_power_of_two() + 1;
+        let mut grid = vec![0.; size * size];
+        let last = size - 1;
+        let corner = rand.get_map_float() as f64;
+        for (x, y) in [(0, 0), (last, 0), (0, last), (last, last)] {
+            grid[y * size + x] = if wrap {
+                corner
+            } else {
+                rand.get_map_float() as f64
+            };
+        }
+
+        let mut step = last;
+        let mut amplitude = 0.5;
+        while step > 1 {
+            let half = step / 2;
+            for y in (half..size).step_by(step) {
+                for x in (half..size).step_by(step) {
+                    let average = (grid[(y - half) * size + x - half]
+                        + grid[(y - half) * size + x + half]
+                        + grid[(y + half) * size + x - half]
+                        + grid[(y + half) * size + x + half])
+                        / 4.;
+                    grid[y * size + x] = average + displacement(rand, amplitude);
+                }
+            }
+            for y in (0..size).step_by(half) {
+                // edge centers sit between the corners, offset by half on every other row
+                let start = (y + half) % step;
+                for x in (start..size).step_by(step) {
+                    if wrap {
+                        if x == last || y == last {
+                            continue;
+                        }
+                        let up = (y + last - half) % last;
+                        let left = (x + last - half) % last;
+                        let average = (grid[up * size + x]
+                            + grid[(y + half) * size + x]
+                            + grid[y * size + left]
+                            + grid[y * size + x + half])
+                            / 4.;
+                        grid[y * size + x] = average + displacement(rand, amplitude);
+                        continue;
+                    }
+                    let mut total = 0.;
+                    let mut count = 0.;
+                    if y >= half {
+                        total += grid[(y - half) * size + x];
+                        count += 1.;
+                    }
+                    if y + half < size {
+                        total += grid[(y + half) * size + x];
+                        count += 1.;
+                    }
+                    if x >= half {
+                        total += grid[y * size + x - half];
+                        count += 1.;
+                    }
+                    if x + half < size {
+                        total += grid[y * size + x + half];
+                        count += 1.;
+                    }
+                    grid[y * size + x] = total / count + displacement(rand, amplitude);
+                }
+            }
+            if wrap {
+                for i in 0..size {
+                    grid[i * size + last] = grid[i * size];
+                    grid[last * size + i] = grid[i];
+                }
+            }
+            step = half;
+            amplitude *= roughness;
+        }
+
+        let min = grid.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = grid.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if max > min {
+            for h in grid.iter_mut() {
+                *h = (*h - min) / (max - min);
+            }
+        }
+        DiamondSquare { grid, size, wrap }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f64 {
+        self.grid[y.min(self.size - 1) * self.size + x.min(self.size - 1)]
+    }
+
+    /// Adds the grid onto `area` through the domain warp of `settings`,
+    /// shaped the same way as the height noise. The warp moves the grid by
+    /// the same share of the map as it moves the height noise.
+    pub fn run_mutate(
+        self,
+        height_map: &mut BitImage,
+        area: Rect<usize>,
+        settings: &NoiseSettings,
+        rand: &mut RandStruct,
+        mask: Option<&FalloffMask>,
+    ) {
+        let width = (area.right - area.left) as f64;
+        let height = (area.bottom - area.top) as f64;
+        // the grid is sampled in cells, the height noise in map widths times
+        // the base frequency
+        let cells = width / settings.base_frequency;
+        let period = if self.wrap {
+            Some((self.size - 1) as f64)
+        } else {
+            None
+        };
+        let grid = DomainWarp::new(
+            self,
+            rand,
+            settings.warp_strength * cells,
+            settings.warp_frequency / cells,
+            settings.warp_depth,
+        )
+        .set_period(period);
+        for x in area.left..(area.right + 1) {
+            for y in area.top..(area.bottom + 1) {
+                let nx = x as f64 / width - 0.5;
+                let ny = y as f64 / height - 0.5;
+                let e = grid.get([(x - area.left) as f64, (y - area.top) as f64]);
+                let e = e + height_map.get_ignore(x, y) as f64;
+                let e = settings.shape_height(e, nx, ny, mask);
+                height_map.point_set(x, y, e as f32);
+            }
+        }
+    }
+}
+
+/// Samples the grid between its points, `[x, y]` in grid cells. Points off
+/// the grid wrap around on a wrapping grid and are clamped to its edge
+/// otherwise.
+impl NoiseFn<[f64; 2]> for DiamondSquare {
+    fn get(&self, [x, y]: [f64; 2]) -> f64 {
+        let last = (self.size - 1) as f64;
+        let onto = |v: f64| {
+            if self.wrap {
+                v.rem_euclid(last)
+            } else {
+                v.clamp(0., last)
+            }
+        };
+        let (x, y) = (onto(x), onto(y));
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as usize, y0 as usize);
+        let get = |x: usize, y: usize| DiamondSquare::get(self, x, y);
+        let top = get(x0, y0) * (1. - fx) + get(x0 + 1, y0) * fx;
+        let bottom = get(x0, y0 + 1) * (1. - fx) + get(x0 + 1, y0 + 1) * fx;
+        top * (1. - fy) + bottom * fy
+    }
+}
+
+fn displacement(rand: &mut RandStruct, amplitude: f64) -> f64 {
+    (rand.get_map_float() as f64 * 2. - 1.) * amplitude
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Falloff, FalloffShape};
+
+    #[test]
+    fn grid_rounds_up_to_a_power_of_two_plus_one_and_spans_0_to_1() {
+        let grid = DiamondSquare::new(&mut RandStruct::from_seed(7), 0.5, 40, false);
+        assert_eq!(grid.size, 65);
+        let min = grid.grid.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = grid.grid.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        assert_eq!((min, max), (0., 1.));
+    }
+
+    #[test]
+    fn wrapping_grid_repeats_across_its_edges() {
+        let grid = DiamondSquare::new(&mut RandStruct::from_seed(7), 0.6, 33, true);
+        let last = grid.size - 1;
+        for i in 0..grid.size {
+            assert_eq!(grid.get(i, 0), grid.get(i, last));
+            assert_eq!(grid.get(0, i), grid.get(last, i));
+        }
+    }
+
+    #[test]
+    fn same_seed_same_grid() {
+        let a = DiamondSquare::new(&mut RandStruct::from_seed(3), 0.5, 17, false);
+        let b = DiamondSquare::new(&mut RandStruct::from_seed(3), 0.5, 17, false);
+        assert_eq!(a.grid, b.grid);
+    }
+
+    #[test]
+    fn sampling_matches_the_grid_points_and_blends_between_them() {
+        let grid = DiamondSquare::new(&mut RandStruct::from_seed(7), 0.5, 9, false);
+        assert_eq!(NoiseFn::get(&grid, [3., 5.]), grid.get(3, 5));
+        let between = (grid.get(3, 5) + grid.get(4, 5)) / 2.;
+        assert!((NoiseFn::get(&grid, [3.5, 5.]) - between).abs() < 1e-12);
+        assert_eq!(NoiseFn::get(&grid, [-2., 20.]), grid.get(0, 8));
+    }
+
+    #[test]
+    fn wrapping_grid_samples_wrap() {
+        let grid = DiamondSquare::new(&mut RandStruct::from_seed(7), 0.5, 9, true);
+        assert_eq!(NoiseFn::get(&grid, [-1., 10.]), grid.get(7, 2));
+    }
+
+    #[test]
+    fn warp_moves_the_grid() {
+        let area = Rect {
+            left: 0,
+            top: 0,
+            right: 16,
+            bottom: 16,
+        };
+        let settings = NoiseSettings {
+            falloff: Falloff {
+                shape: FalloffShape::None,
+                ..Falloff::default()
+            },
+            exponent: 1.,
+            island_bias: 0.,
+            ..NoiseSettings::default()
+        };
+        let run = |warp_depth| {
+            let settings = NoiseSettings {
+                warp_depth,
+                ..settings.clone()
+            };
+            let mut rand = RandStruct::from_seed(4);
+            let grid = DiamondSquare::new(&mut rand, 0.5, 17, false);
+            let mut height_map = BitImage::new(16);
+            grid.run_mutate(&mut height_map, area, &settings, &mut rand, None);
+            height_map.get_heightmap_iter().collect::<Vec<f32>>()
+        };
+        let plain = run(0);
+        let grid = DiamondSquare::new(&mut RandStruct::from_seed(4), 0.5, 17, false);
+        assert_eq!(plain[5 * 17 + 3], (grid.get(3, 5) / 2.) as f32);
+        assert_ne!(run(2), plain);
+    }
+}
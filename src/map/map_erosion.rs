@@ -0,0 +1,446 @@
+use bevy::prelude::*;
+
+use crate::{
+    map::{BitImage, Boundary, HeightMapMutator, MutatorContext},
+    randstruct::RandStruct,
+};
+
+/// Droplet based hydraulic erosion. Each droplet rolls downhill picking up
+/// sediment while it speeds up and dropping it again when it slows down or
+/// runs out of water.
+pub struct HydraulicErosion {
+    pub droplet_count: usize,
+    pub droplets_per_frame: usize,
+    pub max_lifetime: usize,
+    /// How much a droplet keeps its old direction instead of following the slope.
+    pub inertia: f32,
+    pub sediment_capacity: f32,
+    pub min_sediment_capacity: f32,
+    pub deposit_speed: f32,
+    pub erode_speed: f32,
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    /// Radius, in cells, of the patch a droplet wears down, centered on its
+    /// own cell.
+    pub erosion_radius: usize,
+    pub initial_water: f32,
+    pub initial_speed: f32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        HydraulicErosion {
+            droplet_count: 200_000,
+            droplets_per_frame: 5_000,
+            max_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity: 4.,
+            min_sediment_capacity: 0.01,
+            deposit_speed: 0.3,
+            erode_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.,
+            erosion_radius: 3,
+            initial_water: 1.,
+            initial_speed: 1.,
+        }
+    }
+}
+
+impl HydraulicErosion {
+    pub fn run_mutate(
+        &self,
+        height_map: &mut BitImage,
+        rand: &mut RandStruct,
+        area: Rect<usize>,
+        droplets: usize,
+    ) {
+        let brush = self.erosion_brush();
+        let width = (area.right - area.left) as f32;
+        let height = (area.bottom - area.top) as f32;
+        for _ in 0..droplets {
+            let x = area.left as f32 + rand.get_map_float() * width;
+            let y = area.top as f32 + rand.get_map_float() * height;
+            self.run_droplet(height_map, &brush, &area, x, y);
+        }
+    }
+
+    fn run_droplet(
+        &self,
+        height_map: &mut BitImage,
+        brush: &[(isize, isize, f32)],
+        area: &Rect<usize>,
+        mut x: f32,
+        mut y: f32,
+    ) {
+        let (mut dir_x, mut dir_y) = (0., 0.);
+        let mut speed = self.initial_speed;
+        let mut water = self.initial_water;
+        let mut sediment = 0.;
+        let width = (area.right - area.left) as f32;
+        let height = (area.bottom - area.top) as f32;
+
+        for _ in 0..self.max_lifetime {
+            let (cell_x, cell_y) = (x as usize, y as usize);
+            let (offset_x, offset_y) = (x - cell_x as f32, y - cell_y as f32);
+            let (old_height, gradient_x, gradient_y) = height_and_gradient(height_map, x, y);
+
+            dir_x = dir_x * self.inertia - gradient_x * (1. - self.inertia);
+            dir_y = dir_y * self.inertia - gradient_y * (1. - self.inertia);
+            let len = (dir_x * dir_x + dir_y * dir_y).sqrt();
+            if len == 0. {
+                break;
+            }
+            dir_x /= len;
+            dir_y /= len;
+            x += dir_x;
+            y += dir_y;
+
+            // on a wrapping map the droplet comes back in on the other side
+            if height_map.boundary() == Boundary::Wrap {
+                x = area.left as f32 + (x - area.left as f32).rem_euclid(width);
+                y = area.top as f32 + (y - area.top as f32).rem_euclid(height);
+            }
+            // the droplet needs a full cell to sample from, so it stops one short
+            // of the right and bottom edges
+            if x < area.left as f32
+                || y < area.top as f32
+                || x >= area.right as f32
+                || y >= area.bottom as f32
+            {
+                break;
+            }
+
+            let (new_height, _, _) = height_and_gradient(height_map, x, y);
+            let delta_height = new_height - old_height;
+
+            let capacity = (-delta_height * speed * water * self.sediment_capacity)
+                .max(self.min_sediment_capacity);
+
+            if sediment > capacity || delta_height > 0. {
+                // going uphill fills the pit behind the droplet, otherwise drop
+                // a share of whatever is over capacity
+                let amount = if delta_height > 0. {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * self.deposit_speed
+                };
+                sediment -= amount;
+                deposit(height_map, cell_x, cell_y, offset_x, offset_y, amount);
+            } else {
+                let amount = ((capacity - sediment) * self.erode_speed).min(-delta_height);
+                sediment += erode(height_map, brush, area, cell_x, cell_y, amount);
+            }
+
+            speed = (speed * speed + delta_height * self.gravity).max(0.).sqrt();
+            water *= 1. - self.evaporate_speed;
+        }
+    }
+
+    /// Offsets and weights of the cells within `erosion_radius`, the center
+    /// one included and weighted the most. Weights sum to 1.
+    fn erosion_brush(&self) -> Vec<(isize, isize, f32)> {
+        let radius = self.erosion_radius as isize;
+        let mut brush = Vec::new();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let distance = ((dx * dx + dy * dy) as f32).sqrt();
+                if distance <= radius as f32 {
+                    brush.push((dx, dy, 1. - distance / (radius as f32 + 1.)));
+                }
+            }
+        }
+        let total: f32 = brush.iter().map(|(_, _, w)| w).sum();
+        for (_, _, w) in brush.iter_mut() {
+            *w /= total;
+        }
+        brush
+    }
+}
+
+/// Bilinear height and gradient at a point between cells.
+fn height_and_gradient(height_map: &BitImage, x: f32, y: f32) -> (f32, f32, f32) {
+    let (cx, cy) = (x as usize, y as usize);
+    let (u, v) = (x - cx as f32, y - cy as f32);
+    let nw = height_map.get_ignore(cx, cy);
+    let ne = height_map.get_ignore(cx + 1, cy);
+    let sw = height_map.get_ignore(cx, cy + 1);
+    let se = height_map.get_ignore(cx + 1, cy + 1);
+
+    let gradient_x = (ne - nw) * (1. - v) + (se - sw) * v;
+    let gradient_y = (sw - nw) * (1. - u) + (se - ne) * u;
+    let height = nw * (1. - u) * (1. - v) + ne * u * (1. - v) + sw * (1. - u) * v + se * u * v;
+    (height, gradient_x, gradient_y)
+}
+
+fn deposit(height_map: &mut BitImage, x: usize, y: usize, u: f32, v: f32, amount: f32) {
+    height_map.point_raise(x, y, amount * (1. - u) * (1. - v));
+    height_map.point_raise(x + 1, y, amount * u * (1. - v));
+    height_map.point_raise(x, y + 1, amount * (1. - u) * v);
+    height_map.point_raise(x + 1, y + 1, amount * u * v);
+}
+
+/// Wears the cells under the brush down by up to `amount` in total without
+/// digging below zero. Returns how much was actually removed.
+fn erode(
+    height_map: &mut BitImage,
+    brush: &[(isize, isize, f32)],
+    area: &Rect<usize>,
+    x: usize,
+    y: usize,
+    amount: f32,
+) -> f32 {
+    let mut removed = 0.;
+    for (dx, dy, weight) in brush {
+        let (bx, by) = match height_map.boundary() {
+            Boundary::Wrap => match height_map.wrap_coords(x as isize + dx, y as isize + dy) {
+                Some((bx, by)) => (bx as isize, by as isize),
+                None => continue,
+            },
+            Boundary::Clamp => (x as isize + dx, y as isize + dy),
+        };
+        if bx < area.left as isize
+            || by < area.top as isize
+            || bx > area.right as isize
+            || by > area.bottom as isize
+        {
+            continue;
+        }
+        let (bx, by) = (bx as usize, by as usize);
+        let current = height_map.get_ignore(bx, by);
+        let wear = (amount * weight).min(current.max(0.));
+        height_map.point_raise(bx, by, -wear);
+        removed += wear;
+    }
+    removed
+}
+
+impl HeightMapMutator for HydraulicErosion {
+    fn name(&self) -> &'static str {
+        "hydraulic_erosion"
+    }
+
+    fn work(&self) -> usize {
+        self.droplet_count
+    }
+
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, done: usize) -> usize {
+        let droplets = self
+            .droplets_per_frame
+            .min(self.droplet_count.saturating_sub(done));
+        self.run_mutate(height_map, ctx.rand, ctx.area, droplets);
+        droplets
+    }
+}
+
+/// Slumps cliffs steeper than the talus angle, handing part of the excess to
+/// the lower neighbors on every iteration.
+pub struct ThermalErosion {
+    pub iterations: usize,
+    /// Steepest slope in degrees that the material holds without slumping.
+    pub talus_angle: f32,
+    /// Share of the excess height moved per iteration.
+    pub amount: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        ThermalErosion {
+            iterations: 20,
+            talus_angle: 40.,
+            amount: 0.5,
+        }
+    }
+}
+
+impl ThermalErosion {
+    /// Height difference between two neighboring cells that matches the talus
+    /// angle, for a map that is scaled by `height_scale` in the world.
+    pub fn talus(&self, height_scale: f32) -> f32 {
+        self.talus_angle.to_radians().tan() / height_scale
+    }
+
+    /// Runs a single iteration over `area`. Every cell is measured against
+    /// the heights from before the iteration and the moves are applied at the
+    /// end, so the scan order doesn't favor any direction.
+    pub fn run_mutate(&self, height_map: &mut BitImage, area: Rect<usize>, talus: f32) {
+        let in_area = |(x, y): &(usize, usize)| {
+            *x >= area.left && *x <= area.right && *y >= area.top && *y <= area.bottom
+        };
+        let size = height_map.edge_size();
+        let mut delta = vec![0f32; size * size];
+        let mut lower = Vec::with_capacity(8);
+        for y in area.top..(area.bottom + 1) {
+            for x in area.left..(area.right + 1) {
+                let h = height_map.get_ignore(x, y);
+                lower.clear();
+                let mut total_excess = 0.;
+                let mut max_excess = 0.;
+                for (nx, ny) in height_map.neighbors(x, y).filter(in_area) {
+                    let limit = if nx != x && ny != y {
+                        talus * std::f32::consts::SQRT_2
+                    } else {
+                        talus
+                    };
+                    let excess = h - height_map.get_ignore(nx, ny) - limit;
+                    if excess > 0. {
+                        lower.push((nx, ny, excess));
+                        total_excess += excess;
+                        max_excess = f32::max(max_excess, excess);
+                    }
+                }
+                if lower.is_empty() {
+                    continue;
+                }
+
+                // moving half the largest excess levels the steepest pair out
+                let moved = self.amount * max_excess / 2.;
+                delta[y * size + x] -= moved;
+                for (nx, ny, excess) in lower.iter() {
+                    delta[ny * size + nx] += moved * excess / total_excess;
+                }
+            }
+        }
+        for (i, d) in delta.into_iter().enumerate() {
+            if d != 0. {
+                height_map.point_raise(i % size, i / size, d);
+            }
+        }
+    }
+}
+
+impl HeightMapMutator for ThermalErosion {
+    fn name(&self) -> &'static str {
+        "thermal_erosion"
+    }
+
+    fn work(&self) -> usize {
+        self.iterations
+    }
+
+    /// One iteration per step.
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        self.run_mutate(height_map, ctx.area, self.talus(ctx.height_scale));
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(size: usize) -> Rect<usize> {
+        Rect {
+            left: 0,
+            top: 0,
+            right: size,
+            bottom: size,
+        }
+    }
+
+    /// A cone peaking at 1 in the middle.
+    fn cone() -> BitImage {
+        let mut height_map = BitImage::new(32);
+        for y in 0..33 {
+            for x in 0..33 {
+                let d = ((x as f32 - 16.).powi(2) + (y as f32 - 16.).powi(2)).sqrt();
+                height_map.point_set(x, y, (1. - d / 16.).max(0.));
+            }
+        }
+        height_map
+    }
+
+    #[test]
+    fn brush_weights_sum_to_1_and_peak_at_the_center() {
+        for radius in [0, 1, 3] {
+            let erosion = HydraulicErosion {
+                erosion_radius: radius,
+                ..HydraulicErosion::default()
+            };
+            let brush = erosion.erosion_brush();
+            let total: f32 = brush.iter().map(|(_, _, w)| w).sum();
+            assert!((total - 1.).abs() < 1e-5);
+            let center = brush.iter().find(|(dx, dy, _)| (*dx, *dy) == (0, 0));
+            let max = brush.iter().map(|(_, _, w)| *w).fold(0., f32::max);
+            assert_eq!(center.map(|(_, _, w)| *w), Some(max));
+        }
+    }
+
+    #[test]
+    fn droplets_are_spread_over_steps() {
+        let erosion = HydraulicErosion {
+            droplet_count: 250,
+            droplets_per_frame: 100,
+            ..HydraulicErosion::default()
+        };
+        let mut height_map = cone();
+        let mut rand = RandStruct::from_seed(5);
+        let mut ctx = MutatorContext {
+            area: area(32),
+            rand: &mut rand,
+            height_scale: 300.,
+            falloff_mask: None,
+        };
+        let mut done = 0;
+        let mut steps = Vec::new();
+        while done < erosion.work() {
+            let did = erosion.step(&mut height_map, &mut ctx, done);
+            steps.push(did);
+            done += did;
+        }
+        assert_eq!(steps, vec![100, 100, 50]);
+    }
+
+    #[test]
+    fn droplets_wear_the_slopes_down() {
+        let erosion = HydraulicErosion::default();
+        let before = cone();
+        let mut height_map = before.clone();
+        erosion.run_mutate(
+            &mut height_map,
+            &mut RandStruct::from_seed(5),
+            area(32),
+            2000,
+        );
+        let total = |h: &BitImage| h.get_heightmap_iter().sum::<f32>();
+        assert!(total(&height_map) < total(&before));
+        assert!(height_map.get_heightmap_iter().all(|h| h >= 0.));
+    }
+
+    #[test]
+    fn thermal_erosion_slumps_a_spike_evenly() {
+        let mut height_map = BitImage::new(8);
+        height_map.point_set(4, 4, 1.);
+        let thermal = ThermalErosion::default();
+        let talus = thermal.talus(300.);
+        thermal.run_mutate(&mut height_map, area(8), talus);
+
+        let total: f32 = height_map.get_heightmap_iter().sum();
+        assert!((total - 1.).abs() < 1e-5);
+        assert!(height_map.get_ignore(4, 4) < 1.);
+        // the moves don't depend on the scan order, so opposite sides match
+        for (dx, dy) in [(1, 0), (0, 1), (1, 1), (1, -1)] {
+            let a = height_map.get_ignore((4 + dx) as usize, (4 + dy) as usize);
+            let b = height_map.get_ignore((4 - dx) as usize, (4 - dy) as usize);
+            assert_eq!(a, b);
+        }
+        assert!(height_map.get_ignore(5, 4) > height_map.get_ignore(5, 5));
+    }
+
+    #[test]
+    fn thermal_erosion_leaves_gentle_slopes() {
+        let mut height_map = BitImage::new(8);
+        for y in 0..9 {
+            for x in 0..9 {
+                height_map.point_set(x, y, x as f32 * 0.001);
+            }
+        }
+        let before: Vec<f32> = height_map.get_heightmap_iter().collect();
+        let thermal = ThermalErosion::default();
+        thermal.run_mutate(&mut height_map, area(8), thermal.talus(300.));
+        assert_eq!(
+            height_map.get_heightmap_iter().collect::<Vec<f32>>(),
+            before
+        );
+    }
+}
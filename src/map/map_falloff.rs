@@ -0,0 +1,240 @@
+use bevy::log::warn;
+
+use crate::{
+    config::{parse_numbers, Config},
+    map::GrayImage,
+};
+
+/// How the land is pushed under water towards the edge of the map.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FalloffShape {
+    /// No falloff, land runs edge to edge like a continent.
+    None,
+    /// Distance to the nearest edge, gives square-ish islands.
+    Square,
+    Radial,
+    Diamond,
+    /// Blends between diamond (1), radial (2) and square (large exponents).
+    /// The exponent is above 0.
+    Superellipse(f64),
+    /// `[distance, falloff]` control points, both 0..1, joined by straight
+    /// lines. Distance is radial.
+    Curve(Vec<[f64; 2]>),
+    /// Asset path of a grayscale image, white keeps the land and black sinks it.
+    Mask(String),
+}
+
+impl FalloffShape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FalloffShape::None => "none",
+            FalloffShape::Square => "square",
+            FalloffShape::Radial => "radial",
+            FalloffShape::Diamond => "diamond",
+            FalloffShape::Superellipse(_) => "superellipse",
+            FalloffShape::Curve(_) => "curve",
+            FalloffShape::Mask(_) => "mask",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Falloff {
+    pub shape: FalloffShape,
+    /// Scales how much height the falloff takes away.
+    pub strength: f64,
+    /// Distance from the center, 0..1, where the falloff begins.
+    pub start: f64,
+}
+
+impl Default for Falloff {
+    fn default() -> Self {
+        Falloff {
+            shape: FalloffShape::Square,
+            strength: 1.,
+            start: 0.,
+        }
+    }
+}
+
+impl Falloff {
+    /// Reads the `falloff*` keys, shared by every config that embeds a falloff.
+    pub fn read_config(&mut self, config: &Config) {
+        if let Some(name) = config.get_str("falloff") {
+            let shape = match name {
+                "none" => Some(FalloffShape::None),
+                "square" => Some(FalloffShape::Square),
+                "radial" => Some(FalloffShape::Radial),
+                "diamond" => Some(FalloffShape::Diamond),
+                "superellipse" => {
+                    let mut exponent = 4.;
+                    config.read("falloff_exponent", &mut exponent);
+                    if exponent > 0. {
+                        Some(FalloffShape::Superellipse(exponent))
+                    } else {
+                        warn!("falloff_exponent must be above 0, got {}", exponent);
+                        None
+                    }
+                }
+                "curve" => {
+                    let mut points = vec![[0., 0.], [1., 1.]];
+                    config.read_list("falloff_curve", &mut points, parse_numbers);
+                    Some(FalloffShape::Curve(points))
+                }
+                "mask" => config
+                    .get_str("falloff_mask")
+                    .map(|path| FalloffShape::Mask(path.to_string())),
+                _ => None,
+            };
+            match shape {
+                Some(shape) => self.shape = shape,
+                None => warn!("Bad falloff: `{}`", name),
+            }
+        }
+        config.read("falloff_strength", &mut self.strength);
+        config.read("falloff_start", &mut self.start);
+    }
+
+    /// Height to take away at `nx, ny`, both -0.5..0.5 from the center of the map.
+    /// `mask` is only used by `FalloffShape::Mask`.
+    pub fn get(&self, nx: f64, ny: f64, mask: Option<&FalloffMask>) -> f64 {
+        let (ax, ay) = (nx.abs() * 2., ny.abs() * 2.);
+        let distance = match &self.shape {
+            FalloffShape::None => return 0.,
+            FalloffShape::Mask(_) => {
+                let kept = mask.map_or(1., |m| {
+                    m.sample((nx + 0.5) as f32, (ny + 0.5) as f32) as f64
+                });
+                return self.strength * (1. - kept);
+            }
+            FalloffShape::Square => ax.max(ay),
+            FalloffShape::Radial | FalloffShape::Curve(_) => (ax * ax + ay * ay).sqrt(),
+            FalloffShape::Diamond => ax + ay,
+            FalloffShape::Superellipse(p) => (ax.powf(*p) + ay.powf(*p)).powf(1. / p),
+        };
+        let t = if self.start < 1. {
+            ((distance - self.start) / (1. - self.start)).max(0.)
+        } else {
+            0.
+        };
+        let falloff = match &self.shape {
+            FalloffShape::Curve(points) => sample_curve(points, t),
+            _ => t * t,
+        };
+        self.strength * falloff
+    }
+}
+
+/// Piecewise linear lookup, holding the first and last values past the ends.
+fn sample_curve(points: &[[f64; 2]], t: f64) -> f64 {
+    match points {
+        [] => t * t,
+        [[_, y]] => *y,
+        _ => {
+            if t <= points[0][0] {
+                return points[0][1];
+            }
+            for pair in points.windows(2) {
+                let ([x0, y0], [x1, y1]) = (pair[0], pair[1]);
+                if t <= x1 {
+                    let f = if x1 > x0 { (t - x0) / (x1 - x0) } else { 1. };
+                    return y0 + (y1 - y0) * f;
+                }
+            }
+            points[points.len() - 1][1]
+        }
+    }
+}
+
+/// Brightness of a mask image, 0..1.
+pub type FalloffMask = GrayImage;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn falloff(shape: FalloffShape) -> Falloff {
+        Falloff {
+            shape,
+            ..Falloff::default()
+        }
+    }
+
+    fn read(text: &str) -> Falloff {
+        let mut falloff = Falloff::default();
+        falloff.read_config(&Config::parse("test", text).unwrap());
+        falloff
+    }
+
+    #[test]
+    fn shapes_reach_full_strength_at_their_edge() {
+        assert_eq!(falloff(FalloffShape::None).get(0.5, 0.5, None), 0.);
+        assert_eq!(falloff(FalloffShape::Square).get(0.5, 0.1, None), 1.);
+        assert_eq!(falloff(FalloffShape::Radial).get(0., -0.5, None), 1.);
+        assert_eq!(falloff(FalloffShape::Diamond).get(0.25, 0.25, None), 1.);
+        for shape in [FalloffShape::Square, FalloffShape::Radial] {
+            assert_eq!(falloff(shape).get(0., 0., None), 0.);
+        }
+    }
+
+    #[test]
+    fn nothing_is_taken_inside_the_start_radius() {
+        let falloff = Falloff {
+            shape: FalloffShape::Radial,
+            strength: 2.,
+            start: 0.5,
+        };
+        assert_eq!(falloff.get(0.2, 0., None), 0.);
+        assert_eq!(falloff.get(0.5, 0., None), 2.);
+        assert!((falloff.get(0.375, 0., None) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn superellipse_spans_diamond_radial_and_square() {
+        let points = [[0.1, 0.3], [0.2, 0.2], [-0.4, 0.05]];
+        for [x, y] in points {
+            let get = |shape| falloff(shape).get(x, y, None);
+            let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+            assert!(close(
+                get(FalloffShape::Superellipse(1.)),
+                get(FalloffShape::Diamond)
+            ));
+            assert!(close(
+                get(FalloffShape::Superellipse(2.)),
+                get(FalloffShape::Radial)
+            ));
+            assert!(
+                (get(FalloffShape::Superellipse(200.)) - get(FalloffShape::Square)).abs() < 0.02
+            );
+        }
+    }
+
+    #[test]
+    fn curves_are_linear_between_points_and_held_past_the_ends() {
+        let points = [[0.2, 0.], [0.6, 0.8], [0.8, 1.]];
+        assert_eq!(sample_curve(&points, 0.1), 0.);
+        assert!((sample_curve(&points, 0.4) - 0.4).abs() < 1e-12);
+        assert!((sample_curve(&points, 0.7) - 0.9).abs() < 1e-12);
+        assert_eq!(sample_curve(&points, 1.), 1.);
+        assert_eq!(sample_curve(&[[0.5, 0.3]], 0.9), 0.3);
+    }
+
+    #[test]
+    fn config_picks_the_shape_and_rejects_bad_ones() {
+        let falloff = read("falloff = superellipse\nfalloff_exponent = 3\nfalloff_start = 0.2");
+        assert_eq!(falloff.shape, FalloffShape::Superellipse(3.));
+        assert_eq!(falloff.start, 0.2);
+        let falloff = read("falloff = curve\nfalloff_curve = 0 0, 0.5 0.1, 1 1");
+        assert_eq!(
+            falloff.shape,
+            FalloffShape::Curve(vec![[0., 0.], [0.5, 0.1], [1., 1.]])
+        );
+        for text in [
+            "falloff = superellipse\nfalloff_exponent = 0",
+            "falloff = blob",
+            "falloff = mask",
+        ] {
+            assert_eq!(read(text).shape, FalloffShape::Square);
+        }
+    }
+}
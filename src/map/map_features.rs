@@ -0,0 +1,295 @@
+use std::fmt;
+
+use crate::map::{sorted_descending, BitImage, FlowField, NO_RECEIVER};
+
+const NONE: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureKind {
+    Peak,
+    Saddle,
+    Ridge,
+    Valley,
+}
+
+impl fmt::Display for FeatureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FeatureKind::Peak => "Peak",
+            FeatureKind::Saddle => "Saddle",
+            FeatureKind::Ridge => "Ridge",
+            FeatureKind::Valley => "Valley",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct TerrainFeature {
+    pub kind: FeatureKind,
+    pub name: String,
+    pub x: usize,
+    pub y: usize,
+    pub height: f32,
+    /// For peaks, the drop to the key saddle. Saddles carry the prominence of
+    /// the peak they separate, ridges and valleys the relief along their path.
+    pub prominence: f32,
+    /// Cells along the line for ridges and valleys, empty for peaks and saddles.
+    pub path: Vec<(usize, usize)>,
+}
+
+pub struct FeatureSettings {
+    /// Peaks below this prominence, and lines with less relief, are ignored
+    /// (in heightmap units).
+    pub min_prominence: f32,
+    /// Number of upstream cells needed before a cell counts as part of a line.
+    pub min_line_flow: u32,
+    /// Lines shorter than this many cells are dropped.
+    pub min_line_length: usize,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> Self {
+        FeatureSettings {
+            min_prominence: 0.02,
+            min_line_flow: 400,
+            min_line_length: 16,
+        }
+    }
+}
+
+pub struct MapFeatures {
+    pub features: Vec<TerrainFeature>,
+}
+
+#[allow(dead_code)]
+impl MapFeatures {
+    pub fn of_kind(&self, kind: FeatureKind) -> impl Iterator<Item = &TerrainFeature> {
+        self.features.iter().filter(move |f| f.kind == kind)
+    }
+}
+
+pub fn find_features(height_map: &BitImage, settings: &FeatureSettings) -> MapFeatures {
+    let mut features = find_peaks_and_saddles(height_map, settings.min_prominence);
+    features.extend(find_lines(height_map, settings, FeatureKind::Valley));
+    features.extend(find_lines(height_map, settings, FeatureKind::Ridge));
+    MapFeatures { features }
+}
+
+/// Sweeps the map from the highest cell down, growing an island for every
+/// local maximum. When two islands meet at a cell, that cell is the key saddle
+/// of the lower island's peak and its height fixes that peak's prominence.
+fn find_peaks_and_saddles(height_map: &BitImage, min_prominence: f32) -> Vec<TerrainFeature> {
+    let size = height_map.edge_size();
+    let heights: Vec<f32> = height_map.get_heightmap_iter().collect();
+    let order = sorted_descending(&heights);
+
+    let mut parent = vec![NONE; heights.len()];
+    let mut peak_of = vec![NONE; heights.len()];
+    // (peak, saddle, prominence)
+    let mut peaks: Vec<(usize, usize, f32)> = Vec::new();
+
+    for &cell in order.iter() {
+        let (x, y) = (cell % size, cell / size);
+        let mut roots = Vec::with_capacity(8);
+        for (nx, ny) in height_map.neighbors(x, y) {
+            let n = ny * size + nx;
+            if parent[n] != NONE {
+                roots.push(find_root(&mut parent, n));
+            }
+        }
+        roots.sort_unstable();
+        roots.dedup();
+
+        if roots.is_empty() {
+            parent[cell] = cell;
+            peak_of[cell] = cell;
+            continue;
+        }
+
+        roots.sort_by(|a, b| heights[peak_of[*b]].total_cmp(&heights[peak_of[*a]]));
+        let main = roots[0];
+        for &other in roots.iter().skip(1) {
+            let peak = peak_of[other];
+            peaks.push((peak, cell, heights[peak] - heights[cell]));
+            parent[other] = main;
+        }
+        parent[cell] = main;
+    }
+
+    // whatever is left standing is the highest point of the map
+    if let Some(&top) = order.first() {
+        let root = find_root(&mut parent, top);
+        let peak = peak_of[root];
+        peaks.push((peak, NONE, heights[peak] - height_map.min_height()));
+    }
+
+    peaks.retain(|(_, _, prominence)| *prominence >= min_prominence);
+    peaks.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+
+    let mut features = Vec::new();
+    let mut saddle_count = 0;
+    for (i, (peak, saddle, prominence)) in peaks.into_iter().enumerate() {
+        features.push(point_feature(
+            FeatureKind::Peak,
+            i + 1,
+            peak,
+            size,
+            heights[peak],
+            prominence,
+        ));
+        if saddle != NONE {
+            saddle_count += 1;
+            features.push(point_feature(
+                FeatureKind::Saddle,
+                saddle_count,
+                saddle,
+                size,
+                heights[saddle],
+                prominence,
+            ));
+        }
+    }
+    features
+}
+
+/// Valleys are the cells that collect the most downhill flow, ridges are the
+/// same thing on the upside-down map.
+fn find_lines(
+    height_map: &BitImage,
+    settings: &FeatureSettings,
+    kind: FeatureKind,
+) -> Vec<TerrainFeature> {
+    let size = height_map.edge_size();
+    let sign = if kind == FeatureKind::Ridge { -1. } else { 1. };
+    let heights: Vec<f32> = height_map.get_heightmap_iter().map(|h| h * sign).collect();
+
+    let FlowField { receiver, flow } = FlowField::new(height_map, &heights);
+
+    let on_line = |cell: usize| flow[cell] >= settings.min_line_flow;
+    let mut has_upstream = vec![false; heights.len()];
+    for cell in 0..heights.len() {
+        if on_line(cell) && receiver[cell] != NO_RECEIVER {
+            has_upstream[receiver[cell]] = true;
+        }
+    }
+
+    let mut visited = vec![false; heights.len()];
+    let mut paths: Vec<Vec<usize>> = Vec::new();
+    for head in (0..heights.len()).filter(|&c| on_line(c) && !has_upstream[c]) {
+        let mut path = Vec::new();
+        let mut cell = head;
+        loop {
+            path.push(cell);
+            if visited[cell] {
+                break;
+            }
+            visited[cell] = true;
+            cell = receiver[cell];
+            if cell == NO_RECEIVER || !on_line(cell) {
+                break;
+            }
+        }
+        if path.len() >= settings.min_line_length {
+            paths.push(path);
+        }
+    }
+    let relief = |path: &Vec<usize>| {
+        let (low, high) = path.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &c| {
+            (lo.min(heights[c]), hi.max(heights[c]))
+        });
+        high - low
+    };
+    // lines wandering across flat ground are not worth naming
+    paths.retain(|path| relief(path) >= settings.min_prominence);
+    paths.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+
+    paths
+        .into_iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let mid = path[path.len() / 2];
+            let mut feature =
+                point_feature(kind, i + 1, mid, size, heights[mid] * sign, relief(&path));
+            feature.path = path.into_iter().map(|c| (c % size, c / size)).collect();
+            feature
+        })
+        .collect()
+}
+
+fn point_feature(
+    kind: FeatureKind,
+    number: usize,
+    cell: usize,
+    size: usize,
+    height: f32,
+    prominence: f32,
+) -> TerrainFeature {
+    TerrainFeature {
+        kind,
+        name: format!("{} {}", kind, number),
+        x: cell % size,
+        y: cell / size,
+        height,
+        prominence,
+        path: Vec::new(),
+    }
+}
+
+fn find_root(parent: &mut [usize], mut cell: usize) -> usize {
+    while parent[cell] != cell {
+        parent[cell] = parent[parent[cell]];
+        cell = parent[cell];
+    }
+    cell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flat ground at 0 with a ridge across the middle: a peak at 1 and a
+    /// lower one at 0.8, the saddle between them at 0.5.
+    fn two_peaks() -> BitImage {
+        let mut height_map = BitImage::new(8);
+        let ridge = [0.2, 0.6, 1., 0.6, 0.5, 0.6, 0.8, 0.6, 0.2];
+        for (x, h) in ridge.into_iter().enumerate() {
+            height_map.point_set(x, 4, h);
+        }
+        height_map
+    }
+
+    fn features(min_prominence: f32) -> MapFeatures {
+        let settings = FeatureSettings {
+            min_prominence,
+            ..FeatureSettings::default()
+        };
+        find_features(&two_peaks(), &settings)
+    }
+
+    #[test]
+    fn prominence_is_the_drop_to_the_key_saddle() {
+        let features = features(0.1);
+        let peaks: Vec<_> = features.of_kind(FeatureKind::Peak).collect();
+        assert_eq!(peaks.len(), 2);
+        assert_eq!((peaks[0].x, peaks[0].y), (2, 4));
+        assert!((peaks[0].prominence - 1.).abs() < 1e-6);
+        assert_eq!((peaks[1].x, peaks[1].y), (6, 4));
+        assert!((peaks[1].prominence - 0.3).abs() < 1e-6);
+
+        let saddles: Vec<_> = features.of_kind(FeatureKind::Saddle).collect();
+        assert_eq!(saddles.len(), 1);
+        assert_eq!((saddles[0].x, saddles[0].y), (4, 4));
+        assert!((saddles[0].prominence - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn peaks_below_min_prominence_are_dropped() {
+        let features = features(0.5);
+        let peaks: Vec<_> = features.of_kind(FeatureKind::Peak).collect();
+        assert_eq!(peaks.len(), 1);
+        assert_eq!(peaks[0].name, "Peak 1");
+        assert_eq!(features.of_kind(FeatureKind::Saddle).count(), 0);
+    }
+}
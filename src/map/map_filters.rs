@@ -0,0 +1,584 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::Config,
+    map::{BitImage, Boundary, HeightMapMutator, MutatorContext},
+};
+
+/// Square convolution kernel with an odd side length.
+#[derive(Clone, Debug)]
+pub struct Kernel {
+    size: usize,
+    weights: Vec<f32>,
+}
+
+impl Kernel {
+    /// `weights` in row order, `size * size` of them.
+    pub fn new(size: usize, weights: Vec<f32>) -> Result<Self, String> {
+        if size % 2 != 1 {
+            return Err(format!("Kernel size must be odd, got {}", size));
+        }
+        if weights.len() != size * size {
+            return Err(format!(
+                "Kernel of size {} needs {} weights, got {}",
+                size,
+                size * size,
+                weights.len()
+            ));
+        }
+        Ok(Kernel { size, weights })
+    }
+
+    /// Kernel that leaves the map as it is.
+    pub fn identity() -> Self {
+        Kernel {
+            size: 1,
+            weights: vec![1.],
+        }
+    }
+
+    /// Plain 3x3 sharpen, `amount` 0 leaves the map as it is.
+    pub fn sharpen(amount: f32) -> Self {
+        let a = -amount;
+        Kernel {
+            size: 3,
+            weights: vec![0., a, 0., a, 1. + 4. * amount, a, 0., a, 0.],
+        }
+    }
+}
+
+/// Normalized 1D Gaussian reaching out to three sigmas on each side.
+pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    if sigma <= 0. {
+        return vec![1.];
+    }
+    let radius = (sigma * 3.).ceil() as isize;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2. * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = weights.iter().sum();
+    weights.into_iter().map(|w| w / total).collect()
+}
+
+/// Convolves `area` with `kernel`. Reads only the original heights, and
+/// cells past the edge of `area` repeat the nearest cell inside it.
+pub fn convolve(height_map: &mut BitImage, area: Rect<usize>, kernel: &Kernel) {
+    let source = AreaCopy::new(height_map, &area);
+    let radius = (kernel.size / 2) as isize;
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let mut total = 0.;
+            for ky in 0..kernel.size {
+                for kx in 0..kernel.size {
+                    let weight = kernel.weights[ky * kernel.size + kx];
+                    let sx = x as isize + kx as isize - radius;
+                    let sy = y as isize + ky as isize - radius;
+                    total += weight * source.get(sx, sy);
+                }
+            }
+            height_map.point_set(area.left + x, area.top + y, total);
+        }
+    }
+}
+
+/// Convolves `area` with `kernel` along rows and then along columns, the
+/// same as the 2D kernel `kernel * kernel^T` for a fraction of the work.
+pub fn convolve_separable(height_map: &mut BitImage, area: Rect<usize>, kernel: &[f32]) {
+    let source = AreaCopy::new(height_map, &area);
+    let radius = (kernel.len() / 2) as isize;
+    let pass = |source: &AreaCopy, horizontal: bool| {
+        let mut out = Vec::with_capacity(source.data.len());
+        for y in 0..source.height as isize {
+            for x in 0..source.width as isize {
+                let mut total = 0.;
+                for (i, weight) in kernel.iter().enumerate() {
+                    let offset = i as isize - radius;
+                    total += weight
+                        * if horizontal {
+                            source.get(x + offset, y)
+                        } else {
+                            source.get(x, y + offset)
+                        };
+                }
+                out.push(total);
+            }
+        }
+        AreaCopy {
+            data: out,
+            ..*source
+        }
+    };
+    let rows = pass(&source, true);
+    let result = pass(&rows, false);
+    result.write(height_map, &area);
+}
+
+pub fn gaussian_blur(height_map: &mut BitImage, area: Rect<usize>, sigma: f32) {
+    convolve_separable(height_map, area, &gaussian_kernel(sigma));
+}
+
+/// Adds back `amount` times the detail a Gaussian blur would take away.
+/// Differences smaller than `threshold` are left alone, so flat ground
+/// doesn't get its noise amplified.
+pub fn unsharp_mask(
+    height_map: &mut BitImage,
+    area: Rect<usize>,
+    sigma: f32,
+    amount: f32,
+    threshold: f32,
+) {
+    let original = AreaCopy::new(height_map, &area);
+    gaussian_blur(height_map, area, sigma);
+    for y in 0..original.height {
+        for x in 0..original.width {
+            let h = original.data[y * original.width + x];
+            let (px, py) = (area.left + x, area.top + y);
+            let detail = h - height_map.get_ignore(px, py);
+            let sharpened = if detail.abs() >= threshold {
+                h + amount * detail
+            } else {
+                h
+            };
+            height_map.point_set(px, py, sharpened);
+        }
+    }
+}
+
+/// Replaces every cell with the median of the square around it, `radius`
+/// cells out. Spikes and pits vanish while cliffs stay as sharp as they were.
+pub fn median_filter(height_map: &mut BitImage, area: Rect<usize>, radius: usize) {
+    let source = AreaCopy::new(height_map, &area);
+    let r = radius as isize;
+    let mut window = Vec::with_capacity((2 * radius + 1) * (2 * radius + 1));
+    for y in 0..source.height as isize {
+        for x in 0..source.width as isize {
+            window.clear();
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    window.push(source.get(x + dx, y + dy));
+                }
+            }
+            let middle = window.len() / 2;
+            let (_, median, _) = window.select_nth_unstable_by(middle, f32::total_cmp);
+            height_map.point_set(area.left + x as usize, area.top + y as usize, *median);
+        }
+    }
+}
+
+/// Gaussian blur that only averages cells of about the same height. Weights
+/// fall off with distance, over a sigma of half the radius, and with the
+/// height difference, over `range_sigma` in height map units, so both sides
+/// of a cliff or ridge are smoothed on their own.
+pub fn bilateral_filter(
+    height_map: &mut BitImage,
+    area: Rect<usize>,
+    radius: usize,
+    range_sigma: f32,
+) {
+    let source = AreaCopy::new(height_map, &area);
+    let r = radius as isize;
+    let spatial_sigma = (radius as f32 / 2.).max(0.5);
+    let spatial: Vec<f32> = (-r..=r)
+        .flat_map(|dy| (-r..=r).map(move |dx| (dx * dx + dy * dy) as f32))
+        .map(|d2| (-d2 / (2. * spatial_sigma * spatial_sigma)).exp())
+        .collect();
+    let range_div = 2. * range_sigma * range_sigma;
+    for y in 0..source.height as isize {
+        for x in 0..source.width as isize {
+            let h = source.get(x, y);
+            let (mut total, mut weights) = (0., 0.);
+            let mut i = 0;
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let n = source.get(x + dx, y + dy);
+                    let diff = n - h;
+                    let range = if range_div > 0. {
+                        (-diff * diff / range_div).exp()
+                    } else {
+                        1.
+                    };
+                    let weight = spatial[i] * range;
+                    total += n * weight;
+                    weights += weight;
+                    i += 1;
+                }
+            }
+            // the center cell always has weight 1, so weights is never 0
+            height_map.point_set(
+                area.left + x as usize,
+                area.top + y as usize,
+                total / weights,
+            );
+        }
+    }
+}
+
+/// Filter used by the smoothing stage. The last three bring detail out
+/// instead of smoothing it away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmoothingFilter {
+    Gaussian,
+    Median,
+    Bilateral,
+    /// 3x3 sharpen by `amount`.
+    Sharpen,
+    /// Unsharp mask over a Gaussian of `sigma`, by `amount` above `threshold`.
+    Unsharp,
+    /// Convolution with the `kernel` from the config.
+    Kernel,
+}
+
+impl SmoothingFilter {
+    pub const ALL: [SmoothingFilter; 6] = [
+        SmoothingFilter::Gaussian,
+        SmoothingFilter::Median,
+        SmoothingFilter::Bilateral,
+        SmoothingFilter::Sharpen,
+        SmoothingFilter::Unsharp,
+        SmoothingFilter::Kernel,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SmoothingFilter::Gaussian => "gaussian",
+            SmoothingFilter::Median => "median",
+            SmoothingFilter::Bilateral => "bilateral",
+            SmoothingFilter::Sharpen => "sharpen",
+            SmoothingFilter::Unsharp => "unsharp",
+            SmoothingFilter::Kernel => "kernel",
+        }
+    }
+}
+
+impl FromStr for SmoothingFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SmoothingFilter::ALL
+            .into_iter()
+            .find(|filter| filter.name() == s)
+            .ok_or(format!("Unknown smoothing filter: {}", s))
+    }
+}
+
+/// The smoothing, or sharpening, pass after erosion. Loaded from
+/// `assets/config/smoothing.cfg` at startup.
+pub struct SmoothingSettings {
+    pub filter: SmoothingFilter,
+    /// Filter passes, one per frame.
+    pub iterations: usize,
+    /// Gaussian blur radius, in cells, also the blur behind the unsharp mask.
+    pub sigma: f32,
+    /// Window reach of the median and bilateral filters, in cells.
+    pub radius: usize,
+    /// Height difference, in height map units, over which the bilateral
+    /// filter stops mixing cells.
+    pub range_sigma: f32,
+    /// Detail the sharpen and unsharp filters add back, 0 adds none.
+    pub amount: f32,
+    /// Smallest height difference, in height map units, the unsharp filter
+    /// sharpens.
+    pub threshold: f32,
+    pub kernel: Kernel,
+    /// How far each pass moves the ground towards the filtered version, 0..1.
+    pub strength: f32,
+}
+
+impl Default for SmoothingSettings {
+    fn default() -> Self {
+        SmoothingSettings {
+            filter: SmoothingFilter::Gaussian,
+            iterations: 5,
+            sigma: 0.8,
+            radius: 1,
+            range_sigma: 0.02,
+            amount: 0.5,
+            threshold: 0.,
+            kernel: Kernel::identity(),
+            strength: 1.,
+        }
+    }
+}
+
+impl SmoothingSettings {
+    pub fn load() -> Self {
+        let mut settings = SmoothingSettings::default();
+        if let Some(config) = Config::load("smoothing") {
+            config.read("filter", &mut settings.filter);
+            config.read("iterations", &mut settings.iterations);
+            config.read("sigma", &mut settings.sigma);
+            config.read("radius", &mut settings.radius);
+            config.read("range_sigma", &mut settings.range_sigma);
+            config.read("amount", &mut settings.amount);
+            config.read("threshold", &mut settings.threshold);
+            let mut rows: Vec<Vec<f32>> = Vec::new();
+            config.read_list("kernel", &mut rows, |row| {
+                row.split_whitespace().map(|w| w.parse().ok()).collect()
+            });
+            if !rows.is_empty() {
+                match Kernel::new(rows.len(), rows.concat()) {
+                    Ok(kernel) => settings.kernel = kernel,
+                    Err(e) => warn!("smoothing.cfg: {}", e),
+                }
+            }
+            config.read("strength", &mut settings.strength);
+        }
+        settings
+    }
+
+    /// Runs a single pass over `area`.
+    pub fn run_mutate(&self, height_map: &mut BitImage, area: Rect<usize>) {
+        let original = AreaCopy::new(height_map, &area);
+        match self.filter {
+            SmoothingFilter::Gaussian => gaussian_blur(height_map, area, self.sigma),
+            SmoothingFilter::Median => median_filter(height_map, area, self.radius),
+            SmoothingFilter::Bilateral => {
+                bilateral_filter(height_map, area, self.radius, self.range_sigma)
+            }
+            SmoothingFilter::Sharpen => convolve(height_map, area, &Kernel::sharpen(self.amount)),
+            SmoothingFilter::Unsharp => {
+                unsharp_mask(height_map, area, self.sigma, self.amount, self.threshold)
+            }
+            SmoothingFilter::Kernel => convolve(height_map, area, &self.kernel),
+        }
+        if self.strength >= 1. {
+            return;
+        }
+        for y in 0..original.height {
+            for x in 0..original.width {
+                let h = original.data[y * original.width + x];
+                let (px, py) = (area.left + x, area.top + y);
+                let filtered = height_map.get_ignore(px, py);
+                height_map.point_set(px, py, h + (filtered - h) * self.strength);
+            }
+        }
+    }
+}
+
+impl HeightMapMutator for SmoothingSettings {
+    fn name(&self) -> &'static str {
+        "smoothing"
+    }
+
+    fn work(&self) -> usize {
+        self.iterations
+    }
+
+    /// One pass per step.
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        self.run_mutate(height_map, ctx.area);
+        1
+    }
+}
+
+/// Heights of an area, copied out so filters never read their own output.
+struct AreaCopy {
+    data: Vec<f32>,
+    width: usize,
+    height: usize,
+    /// Reads past the edges wrap around, for a tileable map filtered whole.
+    wrap: bool,
+}
+
+impl AreaCopy {
+    fn new(height_map: &BitImage, area: &Rect<usize>) -> Self {
+        let width = area.right - area.left + 1;
+        let height = area.bottom - area.top + 1;
+        let mut data = Vec::with_capacity(width * height);
+        for y in area.top..(area.bottom + 1) {
+            for x in area.left..(area.right + 1) {
+                data.push(height_map.get_ignore(x, y));
+            }
+        }
+        let whole = width == height_map.edge_size() && height == height_map.edge_size();
+        AreaCopy {
+            data,
+            width,
+            height,
+            wrap: whole && height_map.boundary() == Boundary::Wrap,
+        }
+    }
+
+    /// Clamps to the nearest cell inside the area, or wraps around like
+    /// `BitImage::wrap_coords` does.
+    fn get(&self, x: isize, y: isize) -> f32 {
+        let fit = |v: isize, size: usize| {
+            let size = size as isize;
+            if v >= 0 && v < size {
+                v as usize
+            } else if self.wrap && size > 1 {
+                v.rem_euclid(size - 1) as usize
+            } else {
+                v.clamp(0, size - 1) as usize
+            }
+        };
+        self.data[fit(y, self.height) * self.width + fit(x, self.width)]
+    }
+
+    fn write(&self, height_map: &mut BitImage, area: &Rect<usize>) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                height_map.point_set(area.left + x, area.top + y, self.data[y * self.width + x]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 9 by 9 map at `h` everywhere.
+    fn flat(h: f32) -> BitImage {
+        let mut height_map = BitImage::new(8);
+        for y in 0..9 {
+            for x in 0..9 {
+                height_map.point_set(x, y, h);
+            }
+        }
+        height_map
+    }
+
+    fn whole() -> Rect<usize> {
+        Rect {
+            left: 0,
+            top: 0,
+            right: 8,
+            bottom: 8,
+        }
+    }
+
+    fn heights(height_map: &BitImage) -> Vec<f32> {
+        (0..9)
+            .flat_map(|y| (0..9).map(move |x| (x, y)))
+            .map(|(x, y)| height_map.get_ignore(x, y))
+            .collect()
+    }
+
+    #[test]
+    fn gaussian_kernels_are_normalized_and_symmetric() {
+        for sigma in [0.5, 0.8, 2.] {
+            let kernel = gaussian_kernel(sigma);
+            assert_eq!(kernel.len() % 2, 1);
+            assert!((kernel.iter().sum::<f32>() - 1.).abs() < 1e-6);
+            assert!(kernel.iter().eq(kernel.iter().rev()));
+        }
+        assert_eq!(gaussian_kernel(0.), [1.]);
+    }
+
+    #[test]
+    fn kernels_need_an_odd_square() {
+        assert!(Kernel::new(2, vec![0.25; 4]).is_err());
+        assert!(Kernel::new(3, vec![0.; 8]).is_err());
+        assert!(Kernel::new(3, vec![0.; 9]).is_ok());
+    }
+
+    #[test]
+    fn identity_and_zero_sharpen_leave_the_map_alone() {
+        let mut height_map = flat(0.3);
+        height_map.point_set(4, 4, 0.9);
+        height_map.point_set(0, 7, 0.1);
+        let before = heights(&height_map);
+        for kernel in [Kernel::identity(), Kernel::sharpen(0.)] {
+            convolve(&mut height_map, whole(), &kernel);
+            assert_eq!(heights(&height_map), before);
+        }
+    }
+
+    #[test]
+    fn blur_spreads_a_spike_and_keeps_flat_ground() {
+        let mut height_map = flat(0.5);
+        gaussian_blur(&mut height_map, whole(), 1.);
+        assert!(heights(&height_map).iter().all(|h| (h - 0.5).abs() < 1e-6));
+
+        let mut height_map = flat(0.);
+        height_map.point_set(4, 4, 1.);
+        gaussian_blur(&mut height_map, whole(), 1.);
+        let (peak, side) = (height_map.get_ignore(4, 4), height_map.get_ignore(5, 4));
+        assert!(peak < 1. && side > 0. && side < peak);
+        assert!((heights(&height_map).iter().sum::<f32>() - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sharpening_raises_a_spike() {
+        let mut height_map = flat(0.2);
+        height_map.point_set(4, 4, 0.4);
+        convolve(&mut height_map, whole(), &Kernel::sharpen(0.5));
+        assert!((height_map.get_ignore(4, 4) - 0.8).abs() < 1e-6);
+        assert!((height_map.get_ignore(4, 5) - 0.1).abs() < 1e-6);
+        assert!((height_map.get_ignore(0, 0) - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn strength_moves_part_way_to_the_filtered_map() {
+        let mut height_map = flat(0.);
+        height_map.point_set(4, 4, 1.);
+        let settings = SmoothingSettings {
+            filter: SmoothingFilter::Sharpen,
+            strength: 0.5,
+            ..SmoothingSettings::default()
+        };
+        settings.run_mutate(&mut height_map, whole());
+        assert!((height_map.get_ignore(4, 4) - 2.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn smoothing_filters_parse_their_names() {
+        for filter in SmoothingFilter::ALL {
+            assert_eq!(filter.name().parse(), Ok(filter));
+        }
+        assert!("blur".parse::<SmoothingFilter>().is_err());
+    }
+
+    #[test]
+    fn median_removes_a_spike_and_keeps_a_cliff() {
+        let mut height_map = flat(0.2);
+        for y in 0..9 {
+            for x in 5..9 {
+                height_map.point_set(x, y, 0.8);
+            }
+        }
+        let cliff = heights(&height_map);
+        height_map.point_set(2, 4, 1.);
+        height_map.point_set(7, 1, 0.);
+        median_filter(&mut height_map, whole(), 1);
+        assert_eq!(heights(&height_map), cliff);
+    }
+
+    #[test]
+    fn bilateral_smooths_each_side_of_a_cliff_on_its_own() {
+        let mut height_map = flat(0.2);
+        for y in 0..9 {
+            for x in 5..9 {
+                height_map.point_set(x, y, 0.8 + (x + y) as f32 % 2. * 0.01);
+            }
+        }
+        height_map.point_set(2, 4, 0.21);
+        bilateral_filter(&mut height_map, whole(), 2, 0.02);
+        assert!((height_map.get_ignore(4, 4) - 0.2).abs() < 0.005);
+        assert!((height_map.get_ignore(5, 4) - 0.805).abs() < 0.005);
+        assert!(height_map.get_ignore(2, 4) < 0.21);
+        let bumps = (height_map.get_ignore(6, 4) - height_map.get_ignore(7, 4)).abs();
+        assert!(bumps < 0.01);
+
+        let mut blurred = flat(0.2);
+        for y in 0..9 {
+            for x in 5..9 {
+                blurred.point_set(x, y, 0.8);
+            }
+        }
+        gaussian_blur(&mut blurred, whole(), 1.);
+        assert!(blurred.get_ignore(4, 4) > 0.3);
+    }
+
+    #[test]
+    fn bilateral_with_no_range_limit_is_a_blur() {
+        let mut height_map = flat(0.);
+        height_map.point_set(4, 4, 1.);
+        bilateral_filter(&mut height_map, whole(), 1, 0.);
+        let (peak, side) = (height_map.get_ignore(4, 4), height_map.get_ignore(4, 3));
+        assert!(peak < 1. && side > 0.);
+    }
+}
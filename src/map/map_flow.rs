@@ -0,0 +1,90 @@
+use crate::map::BitImage;
+
+/// Receiver of a cell without any lower neighbor.
+pub const NO_RECEIVER: usize = usize::MAX;
+
+/// D8 flow routing: every cell drains into its steepest lower neighbor and
+/// passes on the rain of every cell upstream of it.
+pub struct FlowField {
+    /// Index of the cell each cell drains into, `NO_RECEIVER` for pits.
+    pub receiver: Vec<usize>,
+    /// Number of cells draining through each cell, itself included.
+    pub flow: Vec<u32>,
+}
+
+impl FlowField {
+    /// `heights` is indexed like `height_map`, so callers can route over a
+    /// flipped or edited copy of the ground.
+    pub fn new(height_map: &BitImage, heights: &[f32]) -> Self {
+        let size = height_map.edge_size();
+        let mut receiver = vec![NO_RECEIVER; heights.len()];
+        for (cell, h) in heights.iter().enumerate() {
+            let (x, y) = (cell % size, cell / size);
+            let mut steepest = 0.;
+            for (nx, ny) in height_map.neighbors(x, y) {
+                let n = ny * size + nx;
+                let distance = if nx != x && ny != y {
+                    std::f32::consts::SQRT_2
+                } else {
+                    1.
+                };
+                let slope = (h - heights[n]) / distance;
+                if slope > steepest {
+                    steepest = slope;
+                    receiver[cell] = n;
+                }
+            }
+        }
+
+        let mut flow = vec![1u32; heights.len()];
+        for &cell in sorted_descending(heights).iter() {
+            if receiver[cell] != NO_RECEIVER {
+                flow[receiver[cell]] += flow[cell];
+            }
+        }
+        FlowField { receiver, flow }
+    }
+}
+
+/// Cell indices from the highest to the lowest, ties in index order.
+pub fn sorted_descending(heights: &[f32]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..heights.len()).collect();
+    order.sort_by(|a, b| heights[*b].total_cmp(&heights[*a]).then(a.cmp(b)));
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flow_runs_down_the_steepest_neighbor_and_adds_up() {
+        // a ramp falling towards x = 0, steeper along the axis than diagonally
+        let height_map = BitImage::new(2);
+        let heights: Vec<f32> = (0..9).map(|cell| (cell % 3) as f32).collect();
+        let flow = FlowField::new(&height_map, &heights);
+        for y in 0..3 {
+            assert_eq!(flow.receiver[y * 3 + 2], y * 3 + 1);
+            assert_eq!(flow.receiver[y * 3 + 1], y * 3);
+            assert_eq!(flow.receiver[y * 3], NO_RECEIVER);
+            assert_eq!(flow.flow[y * 3], 3);
+        }
+    }
+
+    #[test]
+    fn diagonal_drops_count_over_their_length() {
+        let height_map = BitImage::new(2);
+        // the diagonal drops 1.3, the straight neighbor 1: 1.3 / 1.41 < 1
+        let mut heights = vec![2.; 9];
+        heights[4] = 1.3;
+        heights[0] = 0.;
+        heights[3] = 0.3;
+        let flow = FlowField::new(&height_map, &heights);
+        assert_eq!(flow.receiver[4], 3);
+    }
+
+    #[test]
+    fn sorted_descending_breaks_ties_by_index() {
+        assert_eq!(sorted_descending(&[1., 3., 1., 2.]), vec![1, 3, 0, 2]);
+    }
+}
@@ -0,0 +1,63 @@
+use bevy::prelude::*;
+
+/// Grayscale values, 0..1 from images, sampled bilinearly. Backs falloff
+/// masks and stamp brushes.
+#[derive(Clone)]
+pub struct GrayImage {
+    data: Vec<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl GrayImage {
+    /// Uses the first channel of each pixel, whatever the format.
+    pub fn from_image(image: &Image) -> Option<Self> {
+        let width = image.texture_descriptor.size.width as usize;
+        let height = image.texture_descriptor.size.height as usize;
+        if width == 0 || height == 0 || image.data.len() < width * height {
+            return None;
+        }
+        let stride = image.data.len() / (width * height);
+        let data = image
+            .data
+            .chunks(stride)
+            .take(width * height)
+            .map(|pixel| pixel[0] as f32 / 255.)
+            .collect();
+        Some(GrayImage {
+            data,
+            width,
+            height,
+        })
+    }
+
+    /// Builds a `size` by `size` image from `f(u, v)`, both -1..1 from the center.
+    pub fn from_fn<F: Fn(f32, f32) -> f32>(size: usize, f: F) -> Self {
+        let size = size.max(2);
+        let step = 2. / (size - 1) as f32;
+        let mut data = Vec::with_capacity(size * size);
+        for y in 0..size {
+            for x in 0..size {
+                data.push(f(x as f32 * step - 1., y as f32 * step - 1.));
+            }
+        }
+        GrayImage {
+            data,
+            width: size,
+            height: size,
+        }
+    }
+
+    /// `u, v` run 0..1 across the image.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.clamp(0., 1.) * (self.width - 1) as f32;
+        let y = v.clamp(0., 1.) * (self.height - 1) as f32;
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let get = |x: usize, y: usize| self.data[y * self.width + x];
+        let top = get(x0, y0) * (1. - fx) + get(x1, y0) * fx;
+        let bottom = get(x0, y1) * (1. - fx) + get(x1, y1) * fx;
+        top * (1. - fy) + bottom * fy
+    }
+}
@@ -0,0 +1,237 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::prelude::*;
+
+use crate::{
+    config::Config,
+    map::{trace_borders, BitImage, Boundary, MapLayer},
+};
+
+/// Which basins count as lakes. Loaded from `assets/config/lakes.cfg` at
+/// startup.
+pub struct LakeSettings {
+    /// Basins covering fewer cells are left dry.
+    pub min_cells: usize,
+    /// Basins shallower than this, in height map units, are left dry.
+    pub min_depth: f32,
+}
+
+impl Default for LakeSettings {
+    fn default() -> Self {
+        LakeSettings {
+            min_cells: 20,
+            min_depth: 0.001,
+        }
+    }
+}
+
+impl LakeSettings {
+    pub fn load() -> Self {
+        let mut settings = LakeSettings::default();
+        if let Some(config) = Config::load("lakes") {
+            config.read("min_cells", &mut settings.min_cells);
+            config.read("min_depth", &mut settings.min_depth);
+        }
+        settings
+    }
+}
+
+#[allow(dead_code)]
+pub struct Lake {
+    pub id: u32,
+    /// Surface height, in height map units.
+    pub level: f32,
+    pub cells: Vec<(usize, usize)>,
+    /// Shore lines in cell coordinates, running between the lake cells and
+    /// the dry ones around them.
+    pub outline: Vec<Vec<Vec2>>,
+    /// Water above the ground summed over the cells, in height map units
+    /// times cells.
+    pub volume: f32,
+    pub max_depth: f32,
+}
+
+/// Closed basins above the sea, filled up to the height where they spill over.
+pub struct Lakes {
+    /// Lake id of every cell, `None` for dry cells and the sea.
+    pub ids: MapLayer<Option<u32>>,
+    /// Indexed by lake id.
+    pub lakes: Vec<Lake>,
+}
+
+#[allow(dead_code)]
+impl Lakes {
+    /// Floods the map inwards from the sea and the map edges, always from the
+    /// lowest shore reached so far. A wrapping map has no edges to drain over.
+    /// Every cell ends up at the height water would have to reach to flow out
+    /// of it; cells that end up higher than the ground hold a lake.
+    pub fn find(height_map: &BitImage, settings: &LakeSettings, water_level: f32) -> Self {
+        let size = height_map.edge_size();
+        let heights: Vec<f32> = height_map.get_heightmap_iter().collect();
+        let mut filled = vec![f32::NAN; heights.len()];
+        let mut queue = BinaryHeap::new();
+        let open_edges = height_map.boundary() == Boundary::Clamp;
+        for (cell, h) in heights.iter().enumerate() {
+            let (x, y) = (cell % size, cell / size);
+            let edge = x == 0 || y == 0 || x == size - 1 || y == size - 1;
+            if *h <= water_level || (open_edges && edge) {
+                filled[cell] = *h;
+                queue.push(Shore(*h, cell));
+            }
+        }
+        while let Some(Shore(level, cell)) = queue.pop() {
+            let (x, y) = (cell % size, cell / size);
+            for (nx, ny) in height_map.neighbors(x, y) {
+                let n = ny * size + nx;
+                if filled[n].is_nan() {
+                    filled[n] = heights[n].max(level);
+                    queue.push(Shore(filled[n], n));
+                }
+            }
+        }
+
+        // lakes are groups of flooded cells sharing the same surface
+        let flooded = |cell: usize| filled[cell] > heights[cell];
+        let mut ids = MapLayer::new(size, None);
+        let mut lakes = Vec::new();
+        let mut seen = vec![false; heights.len()];
+        for start in 0..heights.len() {
+            if seen[start] || !flooded(start) {
+                continue;
+            }
+            let level = filled[start];
+            let mut cells = Vec::new();
+            let mut stack = vec![start];
+            seen[start] = true;
+            while let Some(cell) = stack.pop() {
+                cells.push(cell);
+                let (x, y) = (cell % size, cell / size);
+                for (nx, ny) in height_map.neighbors(x, y) {
+                    let n = ny * size + nx;
+                    if !seen[n] && flooded(n) && filled[n] == level {
+                        seen[n] = true;
+                        stack.push(n);
+                    }
+                }
+            }
+
+            let max_depth = cells.iter().map(|c| level - heights[*c]).fold(0., f32::max);
+            if cells.len() < settings.min_cells || max_depth < settings.min_depth {
+                continue;
+            }
+            let id = lakes.len() as u32;
+            for cell in cells.iter() {
+                ids.set(cell % size, cell / size, Some(id));
+            }
+            lakes.push(Lake {
+                id,
+                level,
+                volume: cells.iter().map(|c| level - heights[*c]).sum(),
+                max_depth,
+                cells: cells.iter().map(|c| (c % size, c / size)).collect(),
+                outline: Vec::new(),
+            });
+        }
+
+        for border in trace_borders(&ids) {
+            if let (None, Some(id)) = border.regions {
+                lakes[id as usize].outline.push(border.points);
+            }
+        }
+        Lakes { ids, lakes }
+    }
+
+    pub fn lake_at(&self, x: usize, y: usize) -> Option<&Lake> {
+        let id = (*self.ids.get(x, y)?)?;
+        self.lakes.get(id as usize)
+    }
+}
+
+/// Queue entry for the flood, the lowest level comes out first.
+struct Shore(f32, usize);
+
+impl PartialEq for Shore {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Shore {}
+
+impl PartialOrd for Shore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Shore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.total_cmp(&self.0).then(other.1.cmp(&self.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 9 by 9 plateau at 1 with a square basin, its floor at 0.2, and a
+    /// channel at 0.6 from its rim out to the edge.
+    fn basin() -> BitImage {
+        let mut height_map = BitImage::new(8);
+        for y in 0..9 {
+            for x in 0..9 {
+                height_map.point_set(x, y, 1.);
+            }
+        }
+        for y in 3..6 {
+            for x in 3..6 {
+                height_map.point_set(x, y, 0.2);
+            }
+        }
+        for x in 6..9 {
+            height_map.point_set(x, 4, 0.6);
+        }
+        height_map
+    }
+
+    #[test]
+    fn basins_fill_to_their_spill_height() {
+        let settings = LakeSettings {
+            min_cells: 1,
+            min_depth: 0.,
+        };
+        let lakes = Lakes::find(&basin(), &settings, 0.);
+        assert_eq!(lakes.lakes.len(), 1);
+        let lake = &lakes.lakes[0];
+        assert_eq!(lake.level, 0.6);
+        assert_eq!(lake.cells.len(), 9);
+        assert!((lake.max_depth - 0.4).abs() < 1e-6);
+        assert!((lake.volume - 3.6).abs() < 1e-5);
+        assert_eq!(lakes.lake_at(4, 4).map(|lake| lake.id), Some(0));
+        assert!(lakes.lake_at(6, 4).is_none());
+        assert!(!lake.outline.is_empty());
+    }
+
+    #[test]
+    fn small_or_shallow_basins_stay_dry() {
+        let small = LakeSettings {
+            min_cells: 10,
+            min_depth: 0.,
+        };
+        assert!(Lakes::find(&basin(), &small, 0.).lakes.is_empty());
+        let shallow = LakeSettings {
+            min_cells: 1,
+            min_depth: 0.5,
+        };
+        assert!(Lakes::find(&basin(), &shallow, 0.).lakes.is_empty());
+    }
+
+    #[test]
+    fn basins_under_the_sea_are_not_lakes() {
+        let settings = LakeSettings {
+            min_cells: 1,
+            min_depth: 0.,
+        };
+        assert!(Lakes::find(&basin(), &settings, 0.5).lakes.is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+/// One value per height map cell, laid out the same way as `BitImage`.
+#[derive(Clone)]
+pub struct MapLayer<T> {
+    data: Vec<T>,
+    edge_size: usize,
+}
+
+#[allow(dead_code)]
+impl<T: Clone> MapLayer<T> {
+    /// A layer with `edge_size` cells per side, all set to `value`.
+    pub fn new(edge_size: usize, value: T) -> Self {
+        MapLayer {
+            data: vec![value; edge_size * edge_size],
+            edge_size,
+        }
+    }
+
+    pub fn edge_size(&self) -> usize {
+        self.edge_size
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&T> {
+        if x >= self.edge_size || y >= self.edge_size {
+            return None;
+        }
+        self.data.get(y * self.edge_size + x)
+    }
+
+    /// Does nothing for cells outside the layer.
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        if x < self.edge_size && y < self.edge_size {
+            self.data[y * self.edge_size + x] = value;
+        }
+    }
+
+    pub fn fill(&mut self, value: T) {
+        self.data.fill(value);
+    }
+
+    /// Cells in row order, with their coordinates.
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize), &T)> {
+        let edge_size = self.edge_size;
+        self.data
+            .iter()
+            .enumerate()
+            .map(move |(i, value)| ((i % edge_size, i / edge_size), value))
+    }
+
+    pub fn map<U: Clone, F: Fn(&T) -> U>(&self, f: F) -> MapLayer<U> {
+        MapLayer {
+            data: self.data.iter().map(f).collect(),
+            edge_size: self.edge_size,
+        }
+    }
+}
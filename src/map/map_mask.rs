@@ -0,0 +1,289 @@
+use std::str::FromStr;
+
+use crate::map::{BitImage, BrushFalloff, BrushStroke, HeightMapMutator, MapLayer, MutatorContext};
+
+/// Range of values with soft edges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub low: f32,
+    pub high: f32,
+    /// Distance past either end over which the weight fades to 0.
+    pub fade: f32,
+}
+
+impl Band {
+    /// 1 inside the band, easing down to 0 at `fade` outside it.
+    pub fn weight(&self, value: f32) -> f32 {
+        let outside = (self.low - value).max(value - self.high);
+        if outside <= 0. {
+            1.
+        } else if self.fade <= 0. {
+            0.
+        } else {
+            BrushFalloff::Smoothstep.weight(outside / self.fade)
+        }
+    }
+}
+
+/// Where a mask gets its weights from.
+#[derive(Clone)]
+pub enum MaskSource {
+    /// Heights in height map units.
+    Height(Band),
+    /// Steepest drop to a neighbor in degrees, measured the way
+    /// `ThermalErosion::talus` measures it.
+    Slope(Band),
+    /// Weights 0..1 painted with brush strokes, in order, onto flat ground
+    /// at 0.
+    Painted(Vec<BrushStroke>),
+    /// Heights the registered mutator of that name builds from the current
+    /// map, clamped to 0..1.
+    Mutator(String),
+}
+
+/// Limits a mutator to part of the map. Every cell ends up between its
+/// original and its mutated height, by the mask's weight there.
+#[derive(Clone)]
+pub struct MutatorMask {
+    pub source: MaskSource,
+    /// Swaps weight `w` for `1 - w`, e.g. to leave the mountains alone.
+    pub invert: bool,
+}
+
+impl MutatorMask {
+    /// Name of the mutator the mask reads its weights from, if it does.
+    pub fn source_mutator(&self) -> Option<&str> {
+        match &self.source {
+            MaskSource::Mutator(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Weights over `ctx.area`, worked out from the heights before the
+    /// mutator runs. `output` is what the source mutator built from them,
+    /// without it a mutator mask lets everything through. Cells outside the
+    /// area are 0.
+    pub fn weights(
+        &self,
+        height_map: &BitImage,
+        output: Option<&BitImage>,
+        ctx: &MutatorContext,
+    ) -> MapLayer<f32> {
+        let area = ctx.area;
+        let mut weights = MapLayer::new(height_map.edge_size(), 0.);
+        let painted = match &self.source {
+            MaskSource::Painted(strokes) => {
+                let mut canvas = height_map.clone();
+                canvas.clear();
+                for stroke in strokes.iter() {
+                    stroke.apply(&mut canvas);
+                }
+                Some(canvas)
+            }
+            _ => None,
+        };
+        for y in area.top..(area.bottom + 1) {
+            for x in area.left..(area.right + 1) {
+                let weight = match &self.source {
+                    MaskSource::Height(band) => band.weight(height_map.get_ignore(x, y)),
+                    MaskSource::Slope(band) => {
+                        let drop = height_map.slope(x, y);
+                        band.weight((drop * ctx.height_scale).atan().to_degrees())
+                    }
+                    MaskSource::Painted(_) => painted.as_ref().map_or(0., |p| p.get_ignore(x, y)),
+                    MaskSource::Mutator(_) => output.map_or(1., |o| o.get_ignore(x, y)),
+                };
+                let weight = weight.clamp(0., 1.);
+                weights.set(x, y, if self.invert { 1. - weight } else { weight });
+            }
+        }
+        weights
+    }
+}
+
+/// Parses the `masks.cfg` form, `height <low> <high> <fade>`,
+/// `slope <low> <high> <fade>`, `mutator <name>` or `painted` with comma
+/// separated `BrushStroke`s, each optionally followed by `invert`.
+impl FromStr for MutatorMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (s, invert) = match s.rsplit_once(char::is_whitespace) {
+            Some((rest, "invert")) => (rest.trim_end(), true),
+            _ => (s, false),
+        };
+        if let Some(strokes) = s.strip_prefix("painted ") {
+            let strokes: Result<Vec<BrushStroke>, String> =
+                strokes.split(',').map(str::parse).collect();
+            return Ok(MutatorMask {
+                source: MaskSource::Painted(strokes?),
+                invert,
+            });
+        }
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let band = |args: &[&str]| -> Result<Band, String> {
+            let numbers: Option<Vec<f32>> = args.iter().map(|a| a.parse().ok()).collect();
+            match numbers.as_deref() {
+                Some(&[low, high, fade]) => Ok(Band { low, high, fade }),
+                _ => Err(format!("Expected `<low> <high> <fade>`, got `{}`", s)),
+            }
+        };
+        let source = match parts.as_slice() {
+            ["height", args @ ..] => MaskSource::Height(band(args)?),
+            ["slope", args @ ..] => MaskSource::Slope(band(args)?),
+            ["mutator", name] => MaskSource::Mutator(name.to_string()),
+            _ => return Err(format!("Unknown mask: {}", s)),
+        };
+        Ok(MutatorMask { source, invert })
+    }
+}
+
+/// Does a step of `mutator` and pulls each cell of `ctx.area` back towards
+/// its height before the step, by `1 - weight`.
+pub fn step_masked(
+    mutator: &dyn HeightMapMutator,
+    weights: &MapLayer<f32>,
+    height_map: &mut BitImage,
+    ctx: &mut MutatorContext,
+    done: usize,
+) -> usize {
+    let area = ctx.area;
+    let width = area.right - area.left + 1;
+    let mut before = Vec::with_capacity(width * (area.bottom - area.top + 1));
+    for y in area.top..(area.bottom + 1) {
+        for x in area.left..(area.right + 1) {
+            before.push(height_map.get_ignore(x, y));
+        }
+    }
+    let done = mutator.step(height_map, ctx, done);
+    for y in area.top..(area.bottom + 1) {
+        for x in area.left..(area.right + 1) {
+            let weight = *weights.get(x, y).unwrap_or(&0.);
+            if weight >= 1. {
+                continue;
+            }
+            let h = before[(y - area.top) * width + (x - area.left)];
+            let mutated = height_map.get_ignore(x, y);
+            height_map.point_set(x, y, h + (mutated - h) * weight);
+        }
+    }
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::*;
+    use crate::randstruct::RandStruct;
+
+    #[test]
+    fn band_is_full_inside_and_fades_outside() {
+        let band = Band {
+            low: 0.2,
+            high: 0.6,
+            fade: 0.1,
+        };
+        assert_eq!(band.weight(0.2), 1.);
+        assert_eq!(band.weight(0.4), 1.);
+        assert_eq!(band.weight(0.6), 1.);
+        assert!((band.weight(0.65) - 0.5).abs() < 1e-5);
+        assert!((band.weight(0.15) - 0.5).abs() < 1e-5);
+        assert_eq!(band.weight(0.7), 0.);
+        assert_eq!(band.weight(0.), 0.);
+    }
+
+    #[test]
+    fn band_without_fade_has_hard_edges() {
+        let band = Band {
+            low: 0.,
+            high: 1.,
+            fade: 0.,
+        };
+        assert_eq!(band.weight(1.), 1.);
+        assert_eq!(band.weight(1.001), 0.);
+    }
+
+    #[test]
+    fn parses_every_source() {
+        let mask: MutatorMask = "height 0.3 1.0 0.1".parse().unwrap();
+        let expected = Band {
+            low: 0.3,
+            high: 1.,
+            fade: 0.1,
+        };
+        assert!(matches!(mask.source, MaskSource::Height(band) if band == expected));
+        assert!(!mask.invert);
+
+        let mask: MutatorMask = "slope 30 90 10 invert".parse().unwrap();
+        assert!(matches!(mask.source, MaskSource::Slope(band) if band.low == 30.));
+        assert!(mask.invert);
+
+        let mask: MutatorMask = " mutator plates ".parse().unwrap();
+        assert_eq!(mask.source_mutator(), Some("plates"));
+
+        let mask: MutatorMask =
+            "painted raise 0.5 0.5 0.1 1 linear, flatten 0.2 0.2 0.1 1 constant 0.3 invert"
+                .parse()
+                .unwrap();
+        assert!(matches!(&mask.source, MaskSource::Painted(strokes) if strokes.len() == 2));
+        assert!(mask.invert);
+    }
+
+    #[test]
+    fn rejects_bad_masks() {
+        for bad in [
+            "height 0.3 1.0",
+            "slope a b c",
+            "mutator",
+            "mutator a b",
+            "blur 1",
+            "painted raise 0.5 0.5",
+            "painted noise 0.5 0.5 0.1 1 linear",
+            "painted raise 0.5 0.5 0.1 1 bumpy",
+            "",
+        ] {
+            assert!(bad.parse::<MutatorMask>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn weights_follow_the_source_and_invert() {
+        let mut height_map = BitImage::new(8);
+        for x in 0..9 {
+            height_map.point_set(x, 0, 1.);
+        }
+        let mut rand = RandStruct::new();
+        let ctx = MutatorContext {
+            area: Rect {
+                left: 0,
+                top: 0,
+                right: 8,
+                bottom: 8,
+            },
+            rand: &mut rand,
+            height_scale: 300.,
+            falloff_mask: None,
+        };
+        let mask: MutatorMask = "height 0.5 2 0".parse().unwrap();
+        let weights = mask.weights(&height_map, None, &ctx);
+        assert_eq!(
+            (weights.get(3, 0), weights.get(3, 4)),
+            (Some(&1.), Some(&0.))
+        );
+
+        let mask: MutatorMask = "painted raise 0.5 0.5 0.25 2 constant invert"
+            .parse()
+            .unwrap();
+        let weights = mask.weights(&height_map, None, &ctx);
+        assert_eq!(
+            (weights.get(4, 4), weights.get(0, 8)),
+            (Some(&0.), Some(&1.))
+        );
+
+        let mask: MutatorMask = "mutator missing".parse().unwrap();
+        let weights = mask.weights(&height_map, None, &ctx);
+        assert_eq!(weights.get(4, 4), Some(&1.));
+    }
+}
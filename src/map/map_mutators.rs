@@ -1,126 +1,526 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use noise::{NoiseFn, Perlin, Seedable};
 
-use crate::{map::BitImage, randstruct::RandStruct};
+use crate::{
+    config::Config,
+    map::{BitImage, Boundary, BrushFalloff, HeightMapMutator, MutatorContext},
+    randstruct::RandStruct,
+};
 
-#[derive(Component)]
+/// A drop of rain running backwards: it climbs to the highest neighbor and
+/// raises the ground under itself on the way, building up ridges.
+#[derive(Component, Clone)]
 pub struct ReverseRain {
+    /// Spawn order, drops are always handled in it.
+    id: u32,
     x: usize,
     y: usize,
     strength: f32,
+    steps_left: usize,
     next_coords: Option<(usize, usize)>,
 }
 
-#[allow(dead_code)]
+/// Reverse rain drops, run as entities by `ReverseRain`'s systems until every
+/// one of them has settled. The registry keeps the stage's place among the
+/// height map mutators, see `MutatorRegistry::register_systems`.
+pub struct ReverseRainSettings {
+    pub drop_count: usize,
+    pub strength: f32,
+    /// Drops that are still climbing after this many steps settle where they are.
+    pub max_steps: usize,
+}
+
+impl Default for ReverseRainSettings {
+    fn default() -> Self {
+        ReverseRainSettings {
+            drop_count: 2000,
+            strength: 0.002,
+            max_steps: 60,
+        }
+    }
+}
+
+impl ReverseRainSettings {
+    /// Name of the stage in the mutator registry.
+    pub const NAME: &'static str = "reverse_rain";
+
+    /// `drop_count` drops on random cells of `area`, numbered in the order
+    /// they are drawn from `rand`.
+    pub fn spawn_drops(&self, rand: &mut RandStruct, area: Rect<usize>) -> Vec<ReverseRain> {
+        let width = (area.right - area.left + 1) as u32;
+        let height = (area.bottom - area.top + 1) as u32;
+        (0..self.drop_count)
+            .map(|id| {
+                let x = area.left + (rand.get_map_u32() % width) as usize;
+                let y = area.top + (rand.get_map_u32() % height) as usize;
+                ReverseRain::new(id as u32, x, y, self.strength, self.max_steps)
+            })
+            .collect()
+    }
+}
+
 impl ReverseRain {
-    pub fn new(x: usize, y: usize, strength: f32) -> Self {
+    pub fn new(id: u32, x: usize, y: usize, strength: f32, steps: usize) -> Self {
         ReverseRain {
+            id,
             x,
             y,
             strength,
+            steps_left: steps,
             next_coords: Some((x, y)),
         }
     }
 
-    pub fn merge(a: ReverseRain, b: ReverseRain) -> ReverseRain {
-        ReverseRain {
-            x: a.x,
-            y: a.y,
-            strength: a.strength + b.strength,
-            next_coords: None,
+    /// Folds `other` into this drop, which keeps its own position.
+    pub fn merge(&mut self, other: &ReverseRain) {
+        self.strength += other.strength;
+        self.steps_left = self.steps_left.max(other.steps_left);
+    }
+
+    /// Picks the highest neighbor of every drop as its next cell, none for
+    /// drops that are out of steps or have nowhere higher to go.
+    pub fn run_check(mut query: Query<&mut ReverseRain>, height_map: Res<BitImage>) {
+        for mut drop in query.iter_mut() {
+            drop.next_coords = if drop.steps_left == 0 {
+                None
+            } else {
+                height_map.compare_to_neighbors(drop.x, drop.y, f32::gt)
+            };
         }
     }
 
+    /// Moves every drop to the cell picked by `run_check`, merging drops that
+    /// end up on the same cell, and raises the ground under the survivors.
+    /// Drops with no next cell have settled and are despawned. Drops are
+    /// handled in spawn order so the result is the same every run.
     pub fn run_mutate(
         mut commands: Commands,
         mut query: Query<(&mut ReverseRain, Entity)>,
         mut height_map: ResMut<BitImage>,
     ) {
-        for (mut drop, entity) in query.iter_mut() {
-            if let Some((x, y)) = drop.next_coords {
-                drop.x = x;
-                drop.y = y;
+        let mut drops: Vec<_> = query.iter_mut().collect();
+        drops.sort_by_key(|(drop, _)| drop.id);
+
+        let mut occupied: HashMap<(usize, usize), usize> = HashMap::default();
+        let mut alive = vec![true; drops.len()];
+        for i in 0..drops.len() {
+            let (x, y) = match drops[i].0.next_coords {
+                Some(coords) => coords,
+                None => {
+                    alive[i] = false;
+                    continue;
+                }
+            };
+            if let Some(&first) = occupied.get(&(x, y)) {
+                let (earlier, later) = drops.split_at_mut(i);
+                earlier[first].0.merge(&later[0].0);
+                alive[i] = false;
+                continue;
+            }
+            occupied.insert((x, y), i);
+            let drop = &mut drops[i].0;
+            drop.x = x;
+            drop.y = y;
+            drop.steps_left = drop.steps_left.saturating_sub(1);
+        }
+
+        for (i, (drop, entity)) in drops.iter().enumerate() {
+            if alive[i] {
                 height_map.point_raise(drop.x, drop.y, drop.strength);
                 height_map.neighbor_raise(drop.x, drop.y, drop.strength / 2.);
             } else {
-                commands.entity(entity).despawn_recursive();
+                commands.entity(*entity).despawn_recursive();
             }
         }
     }
+}
 
-    pub fn run_check(mut query: Query<&mut ReverseRain>, height_map: Res<BitImage>) {
-        for mut drop in query.iter_mut() {
-            drop.next_coords = height_map.compare_to_neighbors(drop.x, drop.y, f32::gt);
+/// Sinks the land towards the map boundary over a band of cells, so coasts
+/// reaching the edge slope down into sea floor instead of ending in a wall.
+/// Loaded from `assets/config/coast.cfg` at startup.
+pub struct CoastSettings {
+    /// Band width in cells, counted in from the edge of the area.
+    pub band_width: usize,
+    /// How the pull fades from full at the edge to none at the inner side
+    /// of the band.
+    pub curve: BrushFalloff,
+    /// Height the ground reaches at the very edge, in height map units.
+    pub depth: f32,
+}
+
+impl Default for CoastSettings {
+    fn default() -> Self {
+        CoastSettings {
+            band_width: 32,
+            curve: BrushFalloff::Smoothstep,
+            depth: 0.,
         }
     }
 }
 
-pub struct PerlinNoise {
-    height_noise: Perlin,
+impl CoastSettings {
+    pub fn load() -> Self {
+        let mut settings = CoastSettings::default();
+        if let Some(config) = Config::load("coast") {
+            config.read("band_width", &mut settings.band_width);
+            config.read("curve", &mut settings.curve);
+            config.read("depth", &mut settings.depth);
+        }
+        settings
+    }
+
+    /// Only lowers the ground, sea floor already below `depth` stays put.
+    /// Wrapping maps have no edge to slope down to and are left alone.
+    pub fn run_mutate(&self, height_map: &mut BitImage, area: Rect<usize>) {
+        if height_map.boundary() == Boundary::Wrap {
+            return;
+        }
+        let band = self.band_width.max(1) as f32;
+        for y in area.top..(area.bottom + 1) {
+            for x in area.left..(area.right + 1) {
+                let edge = (x - area.left)
+                    .min(area.right - x)
+                    .min(y - area.top)
+                    .min(area.bottom - y);
+                let weight = self.curve.weight(edge as f32 / band);
+                if weight <= 0. {
+                    continue;
+                }
+                let h = height_map.get_ignore(x, y);
+                let target = h + (self.depth - h) * weight;
+                if target < h {
+                    height_map.point_set(x, y, target);
+                }
+            }
+        }
+    }
+}
+
+impl HeightMapMutator for CoastSettings {
+    fn name(&self) -> &'static str {
+        "coast"
+    }
+
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        self.run_mutate(height_map, ctx.area);
+        1
+    }
 }
 
-impl PerlinNoise {
-    pub fn new(rand: &mut RandStruct) -> Self {
-        PerlinNoise {
-            height_noise: Perlin::new().set_seed(rand.get_map_u32()),
+/// Quantizes heights into flat steps, for mesas and paddy hills. Loaded from
+/// `assets/config/terrace.cfg` at startup.
+pub struct Terrace {
+    /// Number of steps between `band_low` and `band_high`, 0 turns it off.
+    pub steps: usize,
+    /// 0 leaves the slopes alone, 1 gives flat steps with sheer risers.
+    pub sharpness: f32,
+    /// Heights outside the band are left alone.
+    pub band_low: f32,
+    pub band_high: f32,
+    /// Frequency of the noise that fades the terraces in and out across the
+    /// map, 0 terraces the whole band.
+    pub mask_frequency: f64,
+    /// Pushes the mask towards fully on or fully off, 1 keeps it as it is.
+    pub mask_contrast: f32,
+}
+
+impl Default for Terrace {
+    fn default() -> Self {
+        Terrace {
+            steps: 0,
+            sharpness: 0.7,
+            band_low: 0.02,
+            band_high: 1.,
+            mask_frequency: 4.,
+            mask_contrast: 2.,
+        }
+    }
+}
+
+impl Terrace {
+    pub fn load() -> Self {
+        let mut terrace = Terrace::default();
+        if let Some(config) = Config::load("terrace") {
+            config.read("steps", &mut terrace.steps);
+            config.read("sharpness", &mut terrace.sharpness);
+            config.read("band_low", &mut terrace.band_low);
+            config.read("band_high", &mut terrace.band_high);
+            config.read("mask_frequency", &mut terrace.mask_frequency);
+            config.read("mask_contrast", &mut terrace.mask_contrast);
         }
+        terrace
     }
 
-    pub fn run_mutate(&mut self, mut height_map: ResMut<BitImage>, area: Rect<usize>) {
+    /// The stepped version of `h`, before any masking.
+    pub fn terrace_height(&self, h: f32) -> f32 {
+        let span = self.band_high - self.band_low;
+        if self.steps == 0 || span <= 0. || h < self.band_low || h > self.band_high {
+            return h;
+        }
+        let t = (h - self.band_low) / span * self.steps as f32;
+        let (step, f) = (t.floor(), t.fract());
+        // each step stays flat, then climbs to the next one over the last
+        // `1 - sharpness` of its width
+        let ramp = 1. - self.sharpness.clamp(0., 1.);
+        let f = if ramp > 0. {
+            ((f - (1. - ramp)) / ramp).clamp(0., 1.)
+        } else {
+            0.
+        };
+        self.band_low + (step + f) / self.steps as f32 * span
+    }
+
+    pub fn run_mutate(&self, height_map: &mut BitImage, rand: &mut RandStruct, area: Rect<usize>) {
+        let mask = Perlin::new().set_seed(rand.get_map_u32());
         let width = (area.right - area.left) as f64;
         let height = (area.bottom - area.top) as f64;
-        let base_frequency = 5.;
         for x in area.left..(area.right + 1) {
             for y in area.top..(area.bottom + 1) {
-                let nx = x as f64 / width - 0.5;
-                let ny = y as f64 / height - 0.5;
-                let d = (2. * nx.abs().max(ny.abs())).powf(2.);
-                let nx = (nx) * base_frequency;
-                let ny = (ny) * base_frequency;
-                let e = self.get_height(nx, ny)
-                    + 0.53 * self.get_height(2. * (nx + 1.), 2. * (ny + 1.))
-                    + 0.20 * self.get_height(4. * (nx - 1.), 4. * (ny - 1.))
-                    + 0.12 * self.get_height(8. * nx, 8. * ny)
-                    + 0.05 * self.get_height(32. * nx, 32. * ny);
-                let e = e / (1. + 0.53 + 0.20 + 0.12 + 0.05);
-                let e = (0.9 + e - d) / 2.;
-                let e = e.powf(4.5);
-                height_map.point_set(x, y, e as f32);
+                let h = height_map.get_ignore(x, y);
+                let weight = if self.mask_frequency > 0. {
+                    let point = [
+                        x as f64 / width * self.mask_frequency,
+                        y as f64 / height * self.mask_frequency,
+                    ];
+                    let m = mask.get(point) as f32 / 2. + 0.5;
+                    ((m - 0.5) * self.mask_contrast + 0.5).clamp(0., 1.)
+                } else {
+                    1.
+                };
+                height_map.point_set(x, y, h + (self.terrace_height(h) - h) * weight);
             }
         }
     }
+}
+
+impl HeightMapMutator for Terrace {
+    fn name(&self) -> &'static str {
+        "terrace"
+    }
+
+    fn work(&self) -> usize {
+        usize::from(self.steps > 0)
+    }
 
-    fn get_height(&mut self, x: f64, y: f64) -> f64 {
-        self.height_noise.get([x, y]) / 2. + 0.5
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        self.run_mutate(height_map, ctx.rand, ctx.area);
+        1
     }
 }
 
-pub fn average_by_neighbor(height_map: &mut BitImage, area: Rect<usize>) {
-    for x in area.left..(area.right + 1) {
-        for y in area.top..(area.bottom + 1) {
-            let (total, sum) =
-                height_map.reduce_neighbors(x, y, (0, 0.), |(count, sum), val: f32| {
-                    (count + 1, sum + val)
-                });
-            let average = sum / total as f32;
-            height_map.point_set(x, y, average);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `ReverseRain`'s systems over `height_map`, a frame at a time,
+    /// until every drop has settled.
+    fn rain(height_map: BitImage, drops: Vec<ReverseRain>) -> BitImage {
+        let mut world = World::new();
+        world.insert_resource(height_map);
+        for drop in drops {
+            world.spawn().insert(drop);
         }
+        let mut stage = SystemStage::single_threaded();
+        stage.add_system(ReverseRain::run_check.label("check"));
+        stage.add_system(ReverseRain::run_mutate.after("check"));
+        for _ in 0..100 {
+            if world.query::<&ReverseRain>().iter(&world).count() == 0 {
+                break;
+            }
+            stage.run(&mut world);
+        }
+        assert_eq!(world.query::<&ReverseRain>().iter(&world).count(), 0);
+        world.remove_resource::<BitImage>().unwrap()
     }
-}
 
-pub fn zero_edges(height_map: &mut BitImage, area: Rect<usize>) {
-    let mut fun = |x, y| {
-        height_map.point_set(x, y, 0.);
-    };
-    for x in area.left..(area.right + 1) {
-        if x == area.left || x == area.right {
-            for y in area.top..(area.bottom + 1) {
-                fun(x, y);
+    fn hills() -> BitImage {
+        let mut height_map = BitImage::new(16);
+        for y in 0..17 {
+            for x in 0..17 {
+                let h = (x as f32 * 0.7).sin() + (y as f32 * 0.4).cos();
+                height_map.point_set(x, y, h / 4. + 0.5);
             }
-        } else {
-            for y in [area.top, area.bottom] {
-                fun(x, y);
+        }
+        height_map
+    }
+
+    #[test]
+    fn same_seed_same_ridges() {
+        let settings = ReverseRainSettings {
+            drop_count: 200,
+            ..ReverseRainSettings::default()
+        };
+        let area = Rect {
+            left: 0,
+            top: 0,
+            right: 16,
+            bottom: 16,
+        };
+        let run = || {
+            let drops = settings.spawn_drops(&mut RandStruct::from_seed(11), area);
+            let heights: Vec<f32> = rain(hills(), drops).get_heightmap_iter().collect();
+            heights
+        };
+        let first = run();
+        assert_ne!(first, hills().get_heightmap_iter().collect::<Vec<f32>>());
+        assert_eq!(first, run());
+    }
+
+    #[test]
+    fn drops_on_one_cell_merge() {
+        // climbs along x, one step from the top
+        let mut height_map = BitImage::new(4);
+        for y in 0..5 {
+            for x in 0..5 {
+                height_map.point_set(x, y, if x == 4 { 1. } else { 0. });
+            }
+        }
+        let drops = vec![
+            ReverseRain::new(0, 3, 2, 0.1, 1),
+            ReverseRain::new(1, 3, 2, 0.1, 1),
+        ];
+        let mut world = World::new();
+        world.insert_resource(height_map);
+        for drop in drops {
+            world.spawn().insert(drop);
+        }
+        let mut stage = SystemStage::single_threaded();
+        stage.add_system(ReverseRain::run_check.label("check"));
+        stage.add_system(ReverseRain::run_mutate.after("check"));
+        stage.run(&mut world);
+
+        let drops: Vec<ReverseRain> = world
+            .query::<&ReverseRain>()
+            .iter(&world)
+            .cloned()
+            .collect();
+        assert_eq!(drops.len(), 1);
+        assert_eq!((drops[0].id, drops[0].x), (0, 4));
+        assert!((drops[0].strength - 0.2).abs() < 1e-6);
+        let height_map = world.get_resource::<BitImage>().unwrap();
+        assert!((height_map.get_ignore(drops[0].x, drops[0].y) - 1.2).abs() < 1e-6);
+    }
+
+    fn terrace(steps: usize, sharpness: f32) -> Terrace {
+        Terrace {
+            steps,
+            sharpness,
+            band_low: 0.,
+            band_high: 1.,
+            mask_frequency: 0.,
+            ..Terrace::default()
+        }
+    }
+
+    #[test]
+    fn sharp_terraces_are_flat_steps() {
+        let terrace = terrace(4, 1.);
+        for (h, stepped) in [
+            (0.1, 0.),
+            (0.3, 0.25),
+            (0.49, 0.25),
+            (0.5, 0.5),
+            (0.99, 0.75),
+        ] {
+            assert!((terrace.terrace_height(h) - stepped).abs() < 1e-6, "{}", h);
+        }
+    }
+
+    #[test]
+    fn soft_terraces_climb_between_steps() {
+        let terrace = terrace(4, 0.);
+        for h in [0.1, 0.3, 0.8] {
+            assert!((terrace.terrace_height(h) - h).abs() < 1e-6);
+        }
+        let terrace = self::terrace(2, 0.5);
+        // flat over the first half of each step, a ramp over the second
+        assert!((terrace.terrace_height(0.2) - 0.).abs() < 1e-6);
+        assert!((terrace.terrace_height(0.375) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn heights_outside_the_band_are_left_alone() {
+        let terrace = Terrace {
+            band_low: 0.2,
+            band_high: 0.6,
+            ..self::terrace(4, 1.)
+        };
+        assert_eq!(terrace.terrace_height(0.1), 0.1);
+        assert_eq!(terrace.terrace_height(0.7), 0.7);
+        assert_eq!(self::terrace(0, 1.).terrace_height(0.3), 0.3);
+    }
+
+    #[test]
+    fn unmasked_terraces_cover_the_whole_map() {
+        let mut height_map = BitImage::new(8);
+        for y in 0..9 {
+            for x in 0..9 {
+                height_map.point_set(x, y, (x + y * 9) as f32 / 80.);
             }
+        }
+        let area = Rect {
+            left: 0,
+            top: 0,
+            right: 8,
+            bottom: 8,
         };
+        terrace(4, 1.).run_mutate(&mut height_map, &mut RandStruct::from_seed(1), area);
+        assert!(height_map
+            .get_heightmap_iter()
+            .all(|h| [0., 0.25, 0.5, 0.75, 1.].contains(&h)));
+    }
+
+    fn coast(height_map: &mut BitImage) {
+        let settings = CoastSettings {
+            band_width: 4,
+            curve: BrushFalloff::Linear,
+            depth: 0.1,
+        };
+        let area = Rect {
+            left: 0,
+            top: 0,
+            right: 16,
+            bottom: 16,
+        };
+        settings.run_mutate(height_map, area);
+    }
+
+    #[test]
+    fn coasts_slope_down_to_depth_at_the_edge() {
+        let mut height_map = hills();
+        let before = hills();
+        coast(&mut height_map);
+        for y in 0..17 {
+            for x in 0..17 {
+                let (h, old) = (height_map.get_ignore(x, y), before.get_ignore(x, y));
+                let edge = x.min(16 - x).min(y).min(16 - y);
+                match edge {
+                    0 => assert!((h - 0.1).abs() < 1e-6 || h == old && old < 0.1),
+                    1..=3 => {
+                        let expected = old + (0.1 - old) * (1. - edge as f32 / 4.);
+                        assert!(h == old || (h - expected).abs() < 1e-6)
+                    }
+                    _ => assert_eq!(h, old),
+                }
+                assert!(h <= old);
+            }
+        }
+    }
+
+    #[test]
+    fn coasts_only_lower_the_ground() {
+        let mut height_map = BitImage::new(16);
+        coast(&mut height_map);
+        assert!(height_map.get_heightmap_iter().all(|h| h == 0.));
+    }
+
+    #[test]
+    fn wrapping_maps_have_no_coast() {
+        let mut height_map = hills();
+        height_map.set_boundary(Boundary::Wrap);
+        coast(&mut height_map);
+        assert!(height_map
+            .get_heightmap_iter()
+            .eq(hills().get_heightmap_iter()));
     }
 }
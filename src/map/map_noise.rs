@@ -0,0 +1,738 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use noise::{
+    Billow, MultiFractal, NoiseFn, OpenSimplex, Perlin, RidgedMulti, Seedable, Value, Worley,
+};
+
+use crate::{
+    config::{parse_numbers, Config},
+    map::{
+        BitImage, Boundary, DiamondSquare, Falloff, FalloffMask, HeightMapMutator, MutatorContext,
+    },
+    randstruct::RandStruct,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseKind {
+    Perlin,
+    OpenSimplex,
+    Worley,
+    Value,
+    Billow,
+    RidgedMulti,
+}
+
+impl NoiseKind {
+    pub const ALL: [NoiseKind; 6] = [
+        NoiseKind::Perlin,
+        NoiseKind::OpenSimplex,
+        NoiseKind::Worley,
+        NoiseKind::Value,
+        NoiseKind::Billow,
+        NoiseKind::RidgedMulti,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoiseKind::Perlin => "perlin",
+            NoiseKind::OpenSimplex => "simplex",
+            NoiseKind::Worley => "worley",
+            NoiseKind::Value => "value",
+            NoiseKind::Billow => "billow",
+            NoiseKind::RidgedMulti => "ridged",
+        }
+    }
+
+    /// Billow and ridged noise stack their own octaves, so they are sampled
+    /// once instead of going through the fBm loop.
+    fn is_fractal(&self) -> bool {
+        matches!(self, NoiseKind::Billow | NoiseKind::RidgedMulti)
+    }
+}
+
+impl FromStr for NoiseKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NoiseKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or(format!("Unknown noise kind: {}", s))
+    }
+}
+
+/// What builds the first height map, before any other stage runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeightGenerator {
+    Noise,
+    DiamondSquare,
+}
+
+impl HeightGenerator {
+    pub const ALL: [HeightGenerator; 2] = [HeightGenerator::Noise, HeightGenerator::DiamondSquare];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeightGenerator::Noise => "noise",
+            HeightGenerator::DiamondSquare => "diamond_square",
+        }
+    }
+}
+
+impl FromStr for HeightGenerator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HeightGenerator::ALL
+            .into_iter()
+            .find(|generator| generator.name() == s)
+            .ok_or(format!("Unknown height generator: {}", s))
+    }
+}
+
+/// One noise algorithm mixed into the height noise.
+#[derive(Clone, Copy)]
+pub struct NoiseLayer {
+    pub kind: NoiseKind,
+    /// Share of the final height, relative to the other layers.
+    pub weight: f64,
+    /// Multiplies the base frequency for this layer only.
+    pub frequency: f64,
+}
+
+impl NoiseLayer {
+    /// Parses `kind weight [frequency]`, e.g. `ridged 0.5 0.7`.
+    fn parse(item: &str) -> Option<Self> {
+        let mut parts = item.split_whitespace();
+        let kind = parts.next()?.parse().ok()?;
+        let weight = parts.next().map_or(Some(1.), |w| w.parse().ok())?;
+        let frequency = parts.next().map_or(Some(1.), |f| f.parse().ok())?;
+        Some(NoiseLayer {
+            kind,
+            weight,
+            frequency,
+        })
+    }
+}
+
+/// Shape of the fBm height noise. Loaded from `assets/config/noise.cfg` at
+/// startup and tweaked from the generator menu.
+#[derive(Clone)]
+pub struct NoiseSettings {
+    pub generator: HeightGenerator,
+    pub octaves: usize,
+    /// `[frequency, amplitude]` of each octave, in order. Octaves past the end
+    /// of the list carry on from the last one by `lacunarity` and
+    /// `persistence`, an empty list starts them at `[1, 1]`.
+    pub octave_scales: Vec<[f64; 2]>,
+    /// Frequency multiplier from one octave to the next.
+    pub lacunarity: f64,
+    /// Amplitude multiplier from one octave to the next.
+    pub persistence: f64,
+    pub base_frequency: f64,
+    /// Added to the sample position of each octave before scaling, so octaves
+    /// don't line up at the center of the map. Octaves past the end of the
+    /// list are not offset.
+    pub offsets: Vec<[f64; 2]>,
+    /// Redistribution power, higher values flatten the lowlands.
+    pub exponent: f64,
+    /// Raises everything before the island falloff is taken away.
+    pub island_bias: f64,
+    pub falloff: Falloff,
+    /// Noise algorithms to blend together, by weight.
+    pub layers: Vec<NoiseLayer>,
+    /// How far, in noise space, the domain warp moves sample positions.
+    pub warp_strength: f64,
+    pub warp_frequency: f64,
+    /// Number of times the warp is applied on top of itself, 0 turns it off.
+    pub warp_depth: usize,
+    /// How much the diamond-square displacement keeps from one level to the
+    /// next, higher values give rougher terrain.
+    pub roughness: f64,
+    /// Makes the map repeat, its left and right edges and its top and bottom
+    /// edges match, and neighbor lookups wrap around. The falloff is skipped.
+    pub tileable: bool,
+}
+
+impl Default for NoiseSettings {
+    fn default() -> Self {
+        NoiseSettings {
+            generator: HeightGenerator::Noise,
+            octaves: 5,
+            octave_scales: vec![[1., 1.], [2., 0.53], [4., 0.2], [8., 0.12], [32., 0.05]],
+            lacunarity: 2.,
+            persistence: 0.5,
+            base_frequency: 5.,
+            offsets: vec![[0., 0.], [1., 1.], [-1., -1.]],
+            exponent: 4.5,
+            island_bias: 0.9,
+            falloff: Falloff::default(),
+            layers: vec![NoiseLayer {
+                kind: NoiseKind::Perlin,
+                weight: 1.,
+                frequency: 1.,
+            }],
+            warp_strength: 0.3,
+            warp_frequency: 0.5,
+            warp_depth: 0,
+            roughness: 0.55,
+            tileable: false,
+        }
+    }
+}
+
+impl NoiseSettings {
+    pub fn load() -> Self {
+        let mut settings = NoiseSettings::default();
+        if let Some(config) = Config::load("noise") {
+            config.read("generator", &mut settings.generator);
+            config.read("octaves", &mut settings.octaves);
+            config.read_list("octave_scales", &mut settings.octave_scales, parse_numbers);
+            config.read("lacunarity", &mut settings.lacunarity);
+            config.read("persistence", &mut settings.persistence);
+            config.read("base_frequency", &mut settings.base_frequency);
+            config.read_list("offsets", &mut settings.offsets, parse_numbers);
+            config.read("exponent", &mut settings.exponent);
+            config.read("island_bias", &mut settings.island_bias);
+            settings.falloff.read_config(&config);
+            config.read_list("layers", &mut settings.layers, NoiseLayer::parse);
+            config.read("warp_strength", &mut settings.warp_strength);
+            config.read("warp_frequency", &mut settings.warp_frequency);
+            config.read("warp_depth", &mut settings.warp_depth);
+            config.read("roughness", &mut settings.roughness);
+            config.read("tileable", &mut settings.tileable);
+        }
+        settings
+    }
+
+    /// `[frequency, amplitude]` of `octave`, see `octave_scales`.
+    pub fn octave_scale(&self, octave: usize) -> [f64; 2] {
+        if let Some(scale) = self.octave_scales.get(octave) {
+            return *scale;
+        }
+        let (first, [frequency, amplitude]) = match self.octave_scales.last() {
+            Some(last) => (self.octave_scales.len() - 1, *last),
+            None => (0, [1., 1.]),
+        };
+        let n = (octave - first) as i32;
+        [
+            frequency * self.lacunarity.powi(n),
+            amplitude * self.persistence.powi(n),
+        ]
+    }
+
+    /// Noise space distance after which a tileable map repeats, the width of
+    /// the map at the base frequency.
+    pub fn period(&self) -> Option<f64> {
+        if self.tileable {
+            Some(self.base_frequency)
+        } else {
+            None
+        }
+    }
+
+    /// Turns a raw 0..1 height at `nx, ny`, both -0.5..0.5 from the center of
+    /// the map, into the final height: island bias, falloff and redistribution.
+    /// A tileable map has no edge to sink, so it gets no falloff.
+    pub fn shape_height(&self, e: f64, nx: f64, ny: f64, mask: Option<&FalloffMask>) -> f64 {
+        let d = if self.tileable {
+            0.
+        } else {
+            self.falloff.get(nx, ny, mask)
+        };
+        let e = ((self.island_bias + e - d) / 2.).max(0.);
+        e.powf(self.exponent)
+    }
+}
+
+/// Wraps the `noise` crate generators so a layer can hold any of them.
+enum NoiseSource {
+    Perlin(Perlin),
+    OpenSimplex(OpenSimplex),
+    Worley(Worley),
+    Value(Value),
+    Billow(Billow),
+    RidgedMulti(RidgedMulti),
+}
+
+impl NoiseSource {
+    fn new(kind: NoiseKind, seed: u32, settings: &NoiseSettings) -> Self {
+        match kind {
+            NoiseKind::Perlin => NoiseSource::Perlin(Perlin::new().set_seed(seed)),
+            NoiseKind::OpenSimplex => NoiseSource::OpenSimplex(OpenSimplex::new().set_seed(seed)),
+            NoiseKind::Worley => NoiseSource::Worley(Worley::new().set_seed(seed)),
+            NoiseKind::Value => NoiseSource::Value(Value::new().set_seed(seed)),
+            NoiseKind::Billow => NoiseSource::Billow(
+                Billow::new()
+                    .set_seed(seed)
+                    .set_octaves(settings.octaves)
+                    .set_lacunarity(settings.lacunarity)
+                    .set_persistence(settings.persistence),
+            ),
+            NoiseKind::RidgedMulti => NoiseSource::RidgedMulti(
+                RidgedMulti::new()
+                    .set_seed(seed)
+                    .set_octaves(settings.octaves)
+                    .set_lacunarity(settings.lacunarity),
+            ),
+        }
+    }
+}
+
+impl NoiseFn<[f64; 2]> for NoiseSource {
+    fn get(&self, point: [f64; 2]) -> f64 {
+        match self {
+            NoiseSource::Perlin(n) => n.get(point),
+            NoiseSource::OpenSimplex(n) => n.get(point),
+            NoiseSource::Worley(n) => n.get(point),
+            NoiseSource::Value(n) => n.get(point),
+            NoiseSource::Billow(n) => n.get(point),
+            NoiseSource::RidgedMulti(n) => n.get(point),
+        }
+    }
+}
+
+/// Weighted blend of all noise layers, in 0..1.
+pub struct NoiseLayers {
+    layers: Vec<(NoiseLayer, NoiseSource)>,
+    settings: NoiseSettings,
+}
+
+impl NoiseLayers {
+    /// Seeds one generator per layer, in layer order.
+    pub fn new(rand: &mut RandStruct, settings: &NoiseSettings) -> Self {
+        let layers = settings
+            .layers
+            .iter()
+            .map(|layer| {
+                let source = NoiseSource::new(layer.kind, rand.get_map_u32(), settings);
+                (*layer, source)
+            })
+            .collect();
+        NoiseLayers {
+            layers,
+            settings: settings.clone(),
+        }
+    }
+}
+
+impl NoiseFn<[f64; 2]> for NoiseLayers {
+    fn get(&self, [x, y]: [f64; 2]) -> f64 {
+        let mut total = 0.;
+        let mut total_weight = 0.;
+        for (layer, source) in self.layers.iter() {
+            let (x, y) = (x * layer.frequency, y * layer.frequency);
+            let e = if layer.kind.is_fractal() {
+                get_height(source, x, y)
+            } else {
+                get_fbm(source, x, y, &self.settings)
+            };
+            total += layer.weight * e;
+            total_weight += layer.weight;
+        }
+        if total_weight > 0. {
+            total / total_weight
+        } else {
+            0.
+        }
+    }
+}
+
+/// Moves sample positions around with secondary noise fields before they
+/// reach `source`. With more than one field each one warps the already
+/// warped position again, which folds coastlines over on themselves.
+pub struct DomainWarp<S> {
+    source: S,
+    fields: Vec<[Perlin; 2]>,
+    strength: f64,
+    frequency: f64,
+    period: Option<f64>,
+}
+
+impl<S> DomainWarp<S> {
+    /// Seeds a pair of fields, x and y, for each level of `depth`.
+    pub fn new(
+        source: S,
+        rand: &mut RandStruct,
+        strength: f64,
+        frequency: f64,
+        depth: usize,
+    ) -> Self {
+        DomainWarp {
+            source,
+            fields: warp_fields(rand, depth),
+            strength,
+            frequency,
+            period: None,
+        }
+    }
+
+    /// Makes the warp repeat every `period`, for a source that repeats too.
+    pub fn set_period(self, period: Option<f64>) -> Self {
+        DomainWarp { period, ..self }
+    }
+
+    pub fn warp(&self, point: [f64; 2]) -> [f64; 2] {
+        warp(
+            &self.fields,
+            self.strength,
+            self.frequency,
+            self.period,
+            point,
+        )
+    }
+}
+
+/// Seeds a pair of fields, x and y, for each level of `depth` of `warp`.
+pub fn warp_fields(rand: &mut RandStruct, depth: usize) -> Vec<[Perlin; 2]> {
+    (0..depth)
+        .map(|_| {
+            [
+                Perlin::new().set_seed(rand.get_map_u32()),
+                Perlin::new().set_seed(rand.get_map_u32()),
+            ]
+        })
+        .collect()
+}
+
+/// Moves `point` by `strength` times each pair of `fields` in turn. With a
+/// `period` the warp repeats along with a tileable map.
+pub fn warp(
+    fields: &[[Perlin; 2]],
+    strength: f64,
+    frequency: f64,
+    period: Option<f64>,
+    [mut x, mut y]: [f64; 2],
+) -> [f64; 2] {
+    for [field_x, field_y] in fields.iter() {
+        let field = |field: &Perlin, [x, y]: [f64; 2]| field.get([x * frequency, y * frequency]);
+        let (dx, dy) = match period {
+            Some(period) => (
+                tile(|p| field(field_x, p), [x, y], period, [-1., 1.]),
+                tile(|p| field(field_y, p), [x, y], period, [-1., 1.]),
+            ),
+            None => (field(field_x, [x, y]), field(field_y, [x, y])),
+        };
+        x += strength * dx;
+        y += strength * dy;
+    }
+    [x, y]
+}
+
+impl<S: NoiseFn<[f64; 2]>> NoiseFn<[f64; 2]> for DomainWarp<S> {
+    fn get(&self, point: [f64; 2]) -> f64 {
+        self.source.get(self.warp(point))
+    }
+}
+
+pub struct HeightNoise {
+    source: DomainWarp<NoiseLayers>,
+    period: Option<f64>,
+}
+
+impl HeightNoise {
+    pub fn new(rand: &mut RandStruct, settings: &NoiseSettings) -> Self {
+        let layers = NoiseLayers::new(rand, settings);
+        HeightNoise {
+            // tiling wraps the warped noise as a whole, the warp itself can stay plain
+            source: DomainWarp::new(
+                layers,
+                rand,
+                settings.warp_strength,
+                settings.warp_frequency,
+                settings.warp_depth,
+            ),
+            period: settings.period(),
+        }
+    }
+
+    pub fn run_mutate(
+        &mut self,
+        height_map: &mut BitImage,
+        area: Rect<usize>,
+        settings: &NoiseSettings,
+        mask: Option<&FalloffMask>,
+    ) {
+        let width = (area.right - area.left) as f64;
+        let height = (area.bottom - area.top) as f64;
+        for x in area.left..(area.right + 1) {
+            for y in area.top..(area.bottom + 1) {
+                let nx = x as f64 / width - 0.5;
+                let ny = y as f64 / height - 0.5;
+                let point = [nx * settings.base_frequency, ny * settings.base_frequency];
+                let e = match self.period {
+                    Some(period) => tile(|p| self.source.get(p), point, period, [0., 1.]),
+                    None => self.source.get(point),
+                };
+                // perturbs the base elevation left by the plate stage, if any
+                let e = e + height_map.get_ignore(x, y) as f64;
+                let e = settings.shape_height(e, nx, ny, mask);
+                height_map.point_set(x, y, e as f32);
+            }
+        }
+    }
+}
+
+/// Builds the first height map with the generator picked in the settings.
+impl HeightMapMutator for NoiseSettings {
+    fn name(&self) -> &'static str {
+        "noise"
+    }
+
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        match self.generator {
+            HeightGenerator::Noise => {
+                let mut noise = HeightNoise::new(ctx.rand, self);
+                noise.run_mutate(height_map, ctx.area, self, ctx.falloff_mask);
+            }
+            HeightGenerator::DiamondSquare => {
+                let size = (ctx.area.right - ctx.area.left).max(ctx.area.bottom - ctx.area.top);
+                let wrap = height_map.boundary() == Boundary::Wrap;
+                let grid = DiamondSquare::new(ctx.rand, self.roughness, size + 1, wrap);
+                grid.run_mutate(height_map, ctx.area, self, ctx.rand, ctx.falloff_mask);
+            }
+        }
+        1
+    }
+}
+
+/// Makes `get` repeat every `period` along both axes. The point is wrapped
+/// into one period and blended with the copies of itself shifted back by a
+/// period, each weighted by how close it is to that side, so the value at one
+/// edge meets the value at the other exactly. Blending flattens the noise
+/// towards the middle of `range`, the result is stretched back out to the
+/// contrast of a single sample and clamped to `range`.
+pub fn tile<F>(get: F, [x, y]: [f64; 2], period: f64, [low, high]: [f64; 2]) -> f64
+where
+    F: Fn([f64; 2]) -> f64,
+{
+    let half = period / 2.;
+    let x = (x + half).rem_euclid(period) - half;
+    let y = (y + half).rem_euclid(period) - half;
+    let u = (x + half) / period;
+    let v = (y + half) / period;
+    let blend = get([x, y]) * (1. - u) * (1. - v)
+        + get([x - period, y]) * u * (1. - v)
+        + get([x, y - period]) * (1. - u) * v
+        + get([x - period, y - period]) * u * v;
+    let spread = (((1. - u).powi(2) + u * u) * ((1. - v).powi(2) + v * v)).sqrt();
+    let mean = (low + high) / 2.;
+    (mean + (blend - mean) / spread).clamp(low, high)
+}
+
+/// Sum of all octaves, scaled back to 0..1.
+fn get_fbm(source: &NoiseSource, x: f64, y: f64, settings: &NoiseSettings) -> f64 {
+    let mut total = 0.;
+    let mut total_amplitude = 0.;
+    for octave in 0..settings.octaves {
+        let [frequency, amplitude] = settings.octave_scale(octave);
+        let [ox, oy] = settings.offsets.get(octave).copied().unwrap_or_default();
+        total += amplitude * get_height(source, frequency * (x + ox), frequency * (y + oy));
+        total_amplitude += amplitude;
+    }
+    if total_amplitude > 0. {
+        total / total_amplitude
+    } else {
+        0.
+    }
+}
+
+fn get_height(source: &NoiseSource, x: f64, y: f64) -> f64 {
+    (source.get([x, y]) / 2. + 0.5).clamp(0., 1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The height noise as it was before it had settings.
+    fn baseline(perlin: &Perlin, nx: f64, ny: f64) -> f64 {
+        let get = |x: f64, y: f64| perlin.get([x, y]) / 2. + 0.5;
+        let d = (2. * nx.abs().max(ny.abs())).powf(2.);
+        let (nx, ny) = (nx * 5., ny * 5.);
+        let e = get(nx, ny)
+            + 0.53 * get(2. * (nx + 1.), 2. * (ny + 1.))
+            + 0.20 * get(4. * (nx - 1.), 4. * (ny - 1.))
+            + 0.12 * get(8. * nx, 8. * ny)
+            + 0.05 * get(32. * nx, 32. * ny);
+        let e = e / (1. + 0.53 + 0.20 + 0.12 + 0.05);
+        ((0.9 + e - d) / 2.).powf(4.5)
+    }
+
+    #[test]
+    fn default_settings_give_the_baseline_heights() {
+        let settings = NoiseSettings::default();
+        let area = Rect {
+            left: 0,
+            top: 0,
+            right: 32,
+            bottom: 32,
+        };
+        let mut height_map = BitImage::new(32);
+        HeightNoise::new(&mut RandStruct::from_seed(21), &settings).run_mutate(
+            &mut height_map,
+            area,
+            &settings,
+            None,
+        );
+        let perlin = Perlin::new().set_seed(RandStruct::from_seed(21).get_map_u32());
+        for y in 0..33 {
+            for x in 0..33 {
+                let expected = baseline(&perlin, x as f64 / 32. - 0.5, y as f64 / 32. - 0.5);
+                // the baseline gave NaN where the falloff pushed it below 0
+                let expected = if expected.is_nan() { 0. } else { expected };
+                let h = height_map.get_ignore(x, y) as f64;
+                assert!(
+                    (h - expected).abs() < 1e-6,
+                    "{} {}: {} {}",
+                    x,
+                    y,
+                    h,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tileable_maps_skip_the_falloff() {
+        let settings = NoiseSettings {
+            tileable: true,
+            exponent: 1.,
+            ..NoiseSettings::default()
+        };
+        assert_eq!(settings.shape_height(0.5, 0.5, 0.5, None), 0.7);
+        let settings = NoiseSettings {
+            tileable: false,
+            ..settings
+        };
+        assert!(settings.shape_height(0.5, 0.5, 0.5, None) < 0.7);
+    }
+
+    #[test]
+    fn octaves_past_the_list_follow_lacunarity_and_persistence() {
+        let settings = NoiseSettings {
+            octave_scales: vec![[1., 1.], [3., 0.4]],
+            ..NoiseSettings::default()
+        };
+        assert_eq!(settings.octave_scale(1), [3., 0.4]);
+        assert_eq!(settings.octave_scale(3), [12., 0.1]);
+        let settings = NoiseSettings {
+            octave_scales: Vec::new(),
+            ..settings
+        };
+        assert_eq!(settings.octave_scale(0), [1., 1.]);
+        assert_eq!(settings.octave_scale(2), [4., 0.25]);
+    }
+
+    /// Source that returns the x of the point it is asked for.
+    struct Along;
+
+    impl NoiseFn<[f64; 2]> for Along {
+        fn get(&self, [x, _]: [f64; 2]) -> f64 {
+            x
+        }
+    }
+
+    #[test]
+    fn warp_without_depth_leaves_points_alone() {
+        let warp = DomainWarp::new(Along, &mut RandStruct::from_seed(3), 0.3, 0.5, 0);
+        for point in [[0., 0.], [1.3, -2.7]] {
+            assert_eq!(warp.warp(point), point);
+            assert_eq!(warp.get(point), point[0]);
+        }
+    }
+
+    #[test]
+    fn warp_moves_points_by_at_most_strength_per_level() {
+        let warp = DomainWarp::new(Along, &mut RandStruct::from_seed(3), 0.3, 0.5, 2);
+        let mut moved = false;
+        for i in 0..50 {
+            let point = [i as f64 * 0.37, i as f64 * -0.23];
+            let [x, y] = warp.warp(point);
+            let (dx, dy) = (x - point[0], y - point[1]);
+            assert!(dx.abs() <= 0.6 + 1e-9 && dy.abs() <= 0.6 + 1e-9);
+            moved |= dx != 0. || dy != 0.;
+        }
+        assert!(moved);
+    }
+
+    #[test]
+    fn same_seed_same_warp() {
+        let a = DomainWarp::new(Along, &mut RandStruct::from_seed(9), 0.3, 0.5, 3);
+        let b = DomainWarp::new(Along, &mut RandStruct::from_seed(9), 0.3, 0.5, 3);
+        assert_eq!(a.warp([0.4, 1.1]), b.warp([0.4, 1.1]));
+    }
+
+    #[test]
+    fn periodic_warp_repeats() {
+        let period = 4.;
+        let warp = DomainWarp::new(Along, &mut RandStruct::from_seed(3), 0.3, 0.5, 1)
+            .set_period(Some(period));
+        for point in [[0.3, 0.2], [1.9, -1.1]] {
+            let [x, y] = warp.warp(point);
+            let [sx, sy] = warp.warp([point[0] + period, point[1] - period]);
+            assert!((sx - period - x).abs() < 1e-9 && (sy + period - y).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn noise_kinds_parse_their_names() {
+        for kind in NoiseKind::ALL {
+            assert_eq!(kind.name().parse(), Ok(kind));
+        }
+        assert!("fractal".parse::<NoiseKind>().is_err());
+    }
+
+    #[test]
+    fn layers_parse_with_optional_weight_and_frequency() {
+        let layer = NoiseLayer::parse("ridged 0.5 0.7").unwrap();
+        assert_eq!(
+            (layer.kind, layer.weight, layer.frequency),
+            (NoiseKind::RidgedMulti, 0.5, 0.7)
+        );
+        let layer = NoiseLayer::parse("worley").unwrap();
+        assert_eq!((layer.weight, layer.frequency), (1., 1.));
+        assert!(NoiseLayer::parse("perlin heavy").is_none());
+        assert!(NoiseLayer::parse("").is_none());
+    }
+
+    #[test]
+    fn layers_blend_by_weight() {
+        let layer = |kind, weight| NoiseLayer {
+            kind,
+            weight,
+            frequency: 1.,
+        };
+        let blend = |layers| {
+            let settings = NoiseSettings {
+                layers,
+                ..NoiseSettings::default()
+            };
+            let noise = NoiseLayers::new(&mut RandStruct::from_seed(8), &settings);
+            [[0.3, 0.1], [1.7, -0.4]].map(|point| noise.get(point))
+        };
+        let perlin = blend(vec![layer(NoiseKind::Perlin, 1.)]);
+        let simplex_only = blend(vec![
+            layer(NoiseKind::Perlin, 0.),
+            layer(NoiseKind::OpenSimplex, 2.),
+        ]);
+        assert_eq!(
+            blend(vec![
+                layer(NoiseKind::Perlin, 2.),
+                layer(NoiseKind::OpenSimplex, 0.)
+            ]),
+            perlin
+        );
+        let mixed = blend(vec![
+            layer(NoiseKind::Perlin, 1.),
+            layer(NoiseKind::OpenSimplex, 3.),
+        ]);
+        for i in 0..2 {
+            let expected = (perlin[i] + 3. * simplex_only[i]) / 4.;
+            assert!((mixed[i] - expected).abs() < 1e-12);
+        }
+        assert_eq!(blend(vec![layer(NoiseKind::Value, 0.)]), [0., 0.]);
+    }
+}
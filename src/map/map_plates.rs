@@ -0,0 +1,222 @@
+use bevy::prelude::*;
+use noise::Perlin;
+
+use crate::{
+    config::Config,
+    map::{warp, warp_fields, BitImage, Boundary, HeightMapMutator, MutatorContext},
+    randstruct::RandStruct,
+};
+
+/// Tectonic plates laid out as Voronoi regions. Loaded from
+/// `assets/config/plates.cfg` at startup.
+pub struct PlateSettings {
+    /// 0 skips the plate stage and the height noise starts from flat ground.
+    pub plate_count: usize,
+    /// Chance for each plate to carry a continent instead of ocean floor.
+    pub continental_chance: f32,
+    /// Base elevation of each plate type, added to the height noise.
+    pub continental_height: f32,
+    pub oceanic_height: f32,
+    /// Uplift where plates meet head on at full speed.
+    pub mountain_height: f32,
+    /// Depth of the rift where plates pull apart at full speed.
+    pub rift_depth: f32,
+    /// Width of the boundary zone on each side, as a share of the map size.
+    pub boundary_width: f32,
+    /// Bends the straight Voronoi edges, in map widths.
+    pub boundary_warp: f64,
+}
+
+impl Default for PlateSettings {
+    fn default() -> Self {
+        PlateSettings {
+            plate_count: 12,
+            continental_chance: 0.4,
+            continental_height: 0.1,
+            oceanic_height: -0.25,
+            mountain_height: 0.3,
+            rift_depth: 0.15,
+            boundary_width: 0.05,
+            boundary_warp: 0.08,
+        }
+    }
+}
+
+impl PlateSettings {
+    pub fn load() -> Self {
+        let mut settings = PlateSettings::default();
+        if let Some(config) = Config::load("plates") {
+            config.read("plate_count", &mut settings.plate_count);
+            config.read("continental_chance", &mut settings.continental_chance);
+            config.read("continental_height", &mut settings.continental_height);
+            config.read("oceanic_height", &mut settings.oceanic_height);
+            config.read("mountain_height", &mut settings.mountain_height);
+            config.read("rift_depth", &mut settings.rift_depth);
+            config.read("boundary_width", &mut settings.boundary_width);
+            config.read("boundary_warp", &mut settings.boundary_warp);
+        }
+        settings
+    }
+}
+
+impl HeightMapMutator for PlateSettings {
+    fn name(&self) -> &'static str {
+        "plates"
+    }
+
+    fn work(&self) -> usize {
+        usize::from(self.plate_count > 0)
+    }
+
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        let wrap = height_map.boundary() == Boundary::Wrap;
+        let plates = Plates::new(ctx.rand, self, wrap);
+        plates.run_mutate(height_map, ctx.area, self);
+        1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlateKind {
+    Continental,
+    Oceanic,
+}
+
+/// One plate. Positions are 0..1 across the map, velocities are at most 1.
+#[derive(Clone, Copy, Debug)]
+pub struct Plate {
+    pub center: Vec2,
+    pub velocity: Vec2,
+    pub kind: PlateKind,
+}
+
+/// A plate and the center it was measured from.
+type PlateAt<'a> = (&'a Plate, Vec2);
+
+pub struct Plates {
+    plates: Vec<Plate>,
+    /// Fields for `warp`, which bends the plate boundaries.
+    warp_fields: Vec<[Perlin; 2]>,
+    /// Plates reach across the map edges, for tileable maps.
+    wrap: bool,
+}
+
+impl Plates {
+    pub fn new(rand: &mut RandStruct, settings: &PlateSettings, wrap: bool) -> Self {
+        let plates = (0..settings.plate_count)
+            .map(|_| {
+                let center = Vec2::new(rand.get_map_float(), rand.get_map_float());
+                let angle = rand.get_map_float() * std::f32::consts::TAU;
+                let speed = rand.get_map_float();
+                let kind = if rand.get_map_float() < settings.continental_chance {
+                    PlateKind::Continental
+                } else {
+                    PlateKind::Oceanic
+                };
+                Plate {
+                    center,
+                    velocity: Vec2::new(angle.cos(), angle.sin()) * speed,
+                    kind,
+                }
+            })
+            .collect();
+        Plates {
+            plates,
+            warp_fields: warp_fields(rand, 2),
+            wrap,
+        }
+    }
+
+    /// Nearest and second nearest plate to a point, each with the center it
+    /// was measured from, and the distance from the point to the boundary
+    /// between the two. On a wrapping map the centers can be copies shifted by
+    /// a map width or height.
+    fn nearest(&self, point: Vec2) -> Option<(PlateAt<'_>, PlateAt<'_>, f32)> {
+        let shifts: &[f32] = if self.wrap { &[-1., 0., 1.] } else { &[0.] };
+        let mut first = (f32::INFINITY, None);
+        let mut second = (f32::INFINITY, None);
+        for plate in self.plates.iter() {
+            for sx in shifts {
+                for sy in shifts {
+                    let center = plate.center + Vec2::new(*sx, *sy);
+                    let d = center.distance_squared(point);
+                    if d < first.0 {
+                        second = first;
+                        first = (d, Some((plate, center)));
+                    } else if d < second.0 {
+                        second = (d, Some((plate, center)));
+                    }
+                }
+            }
+        }
+        let (d1, p1) = (first.0, first.1?);
+        let (d2, p2) = (second.0, second.1?);
+        let gap = p1.1.distance(p2.1);
+        if gap == 0. {
+            return Some((p1, p2, 0.));
+        }
+        Some((p1, p2, (d2 - d1) / (2. * gap)))
+    }
+
+    /// Base elevation at `point`, which runs 0..1 across the map.
+    pub fn elevation(&self, point: Vec2, settings: &PlateSettings) -> f32 {
+        let period = if self.wrap { Some(1.) } else { None };
+        let [wx, wy] = warp(
+            &self.warp_fields,
+            settings.boundary_warp,
+            4.,
+            period,
+            [point.x as f64, point.y as f64],
+        );
+        let point = Vec2::new(wx as f32, wy as f32);
+        let ((plate, center), (other, other_center), boundary) = match self.nearest(point) {
+            Some(pair) => pair,
+            None => return 0.,
+        };
+        let base = match plate.kind {
+            PlateKind::Continental => settings.continental_height,
+            PlateKind::Oceanic => settings.oceanic_height,
+        };
+        if boundary >= settings.boundary_width {
+            return base;
+        }
+
+        let t = 1. - boundary / settings.boundary_width;
+        let t = t * t;
+        let normal = (other_center - center).normalize_or_zero();
+        // positive when the plates move towards each other
+        let convergence = (plate.velocity - other.velocity).dot(normal) / 2.;
+        if convergence > 0. {
+            let uplift = settings.mountain_height * convergence * t;
+            match (plate.kind, other.kind) {
+                // ocean floor dives under the continent, leaving a trench
+                (PlateKind::Oceanic, PlateKind::Continental) => base - uplift / 2.,
+                // two ocean plates build a chain of island arcs
+                (PlateKind::Oceanic, PlateKind::Oceanic) => base + uplift / 2.,
+                _ => base + uplift,
+            }
+        } else {
+            base + settings.rift_depth * convergence * t
+        }
+    }
+
+    /// Writes the base elevation over `area`.
+    pub fn run_mutate(
+        &self,
+        height_map: &mut BitImage,
+        area: Rect<usize>,
+        settings: &PlateSettings,
+    ) {
+        let width = (area.right - area.left) as f32;
+        let height = (area.bottom - area.top) as f32;
+        for x in area.left..(area.right + 1) {
+            for y in area.top..(area.bottom + 1) {
+                let point = Vec2::new(
+                    (x - area.left) as f32 / width,
+                    (y - area.top) as f32 / height,
+                );
+                height_map.point_set(x, y, self.elevation(point, settings));
+            }
+        }
+    }
+}
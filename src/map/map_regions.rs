@@ -0,0 +1,420 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    config::Config,
+    map::{BitImage, MapLayer},
+    randstruct::RandStruct,
+};
+
+/// Province layout for the strategy layer. Loaded from
+/// `assets/config/regions.cfg` at startup.
+pub struct RegionSettings {
+    pub region_count: usize,
+    /// Lloyd relaxation passes, each one moves the seeds to the middle of
+    /// their region and grows the regions again, evening out their sizes.
+    pub relax_iterations: usize,
+    /// Keeps regions on land above the water height, water gets no region.
+    pub land_only: bool,
+}
+
+impl Default for RegionSettings {
+    fn default() -> Self {
+        RegionSettings {
+            region_count: 64,
+            relax_iterations: 2,
+            land_only: true,
+        }
+    }
+}
+
+impl RegionSettings {
+    pub fn load() -> Self {
+        let mut settings = RegionSettings::default();
+        if let Some(config) = Config::load("regions") {
+            config.read("region_count", &mut settings.region_count);
+            config.read("relax_iterations", &mut settings.relax_iterations);
+            config.read("land_only", &mut settings.land_only);
+        }
+        settings
+    }
+}
+
+#[allow(dead_code)]
+pub struct Region {
+    pub id: u32,
+    /// Mean position of the region's cells.
+    pub centroid: Vec2,
+    pub cell_count: usize,
+    /// Ids of the regions sharing a border with this one, sorted.
+    pub neighbors: Vec<u32>,
+}
+
+/// Region ids on either side of a border.
+pub type RegionPair = (Option<u32>, Option<u32>);
+
+/// Line between two regions, or between a region and cells without one.
+#[allow(dead_code)]
+pub struct RegionBorder {
+    /// Regions on either side, the smaller one first. `None` sorts first.
+    pub regions: RegionPair,
+    /// Corners between cell centers, in cell coordinates.
+    pub points: Vec<Vec2>,
+}
+
+/// Voronoi regions grown over the height map from seeded points.
+#[allow(dead_code)]
+pub struct Regions {
+    /// Region id of every cell, `None` for cells outside every region.
+    pub ids: MapLayer<Option<u32>>,
+    /// Indexed by region id.
+    pub regions: Vec<Region>,
+    pub borders: Vec<RegionBorder>,
+}
+
+#[allow(dead_code)]
+impl Regions {
+    pub fn generate(
+        height_map: &BitImage,
+        rand: &mut RandStruct,
+        settings: &RegionSettings,
+        water_level: f32,
+    ) -> Self {
+        let open =
+            |x: usize, y: usize| !settings.land_only || height_map.get_ignore(x, y) > water_level;
+        let mut seeds = pick_seeds(height_map.edge_size(), rand, settings.region_count, open);
+        let mut ids = grow_regions(height_map, &seeds, open);
+        for _ in 0..settings.relax_iterations {
+            seeds = relax_seeds(&ids, &seeds);
+            ids = grow_regions(height_map, &seeds, open);
+        }
+
+        let centroids = centroids(&ids, seeds.len());
+        let mut regions: Vec<Region> = centroids
+            .into_iter()
+            .enumerate()
+            .map(|(id, (centroid, cell_count))| Region {
+                id: id as u32,
+                centroid,
+                cell_count,
+                neighbors: Vec::new(),
+            })
+            .collect();
+        let mut neighbors = HashSet::default();
+        for ((x, y), id) in ids.iter() {
+            let id = match id {
+                Some(id) => *id,
+                None => continue,
+            };
+            for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                if let Some(Some(other)) = ids.get(nx, ny) {
+                    if *other != id {
+                        neighbors.insert((id.min(*other), id.max(*other)));
+                    }
+                }
+            }
+        }
+        for (a, b) in neighbors {
+            regions[a as usize].neighbors.push(b);
+            regions[b as usize].neighbors.push(a);
+        }
+        for region in regions.iter_mut() {
+            region.neighbors.sort_unstable();
+        }
+
+        let borders = trace_borders(&ids);
+        Regions {
+            ids,
+            regions,
+            borders,
+        }
+    }
+
+    pub fn region_at(&self, x: usize, y: usize) -> Option<&Region> {
+        let id = (*self.ids.get(x, y)?)?;
+        self.regions.get(id as usize)
+    }
+
+    pub fn are_adjacent(&self, a: u32, b: u32) -> bool {
+        match self.regions.get(a as usize) {
+            Some(region) => region.neighbors.binary_search(&b).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Random open cells, without repeats. Gives up on a seed after a number of
+/// misses, so a map with little land can end up with fewer regions.
+fn pick_seeds<F>(size: usize, rand: &mut RandStruct, count: usize, open: F) -> Vec<(usize, usize)>
+where
+    F: Fn(usize, usize) -> bool,
+{
+    let mut seeds = Vec::with_capacity(count);
+    for _ in 0..count {
+        for _ in 0..100 {
+            let x = rand.get_map_u32() as usize % size;
+            let y = rand.get_map_u32() as usize % size;
+            if open(x, y) && !seeds.contains(&(x, y)) {
+                seeds.push((x, y));
+                break;
+            }
+        }
+    }
+    seeds
+}
+
+/// Grows every seed outwards over open cells at the same pace, so each cell
+/// goes to its nearest seed. Regions don't cross closed cells.
+fn grow_regions<F>(
+    height_map: &BitImage,
+    seeds: &[(usize, usize)],
+    open: F,
+) -> MapLayer<Option<u32>>
+where
+    F: Fn(usize, usize) -> bool,
+{
+    let size = height_map.edge_size();
+    let mut ids = MapLayer::new(size, None);
+    let mut distances = MapLayer::new(size, u32::MAX);
+    let mut queue = BinaryHeap::new();
+    for (id, (x, y)) in seeds.iter().enumerate() {
+        distances.set(*x, *y, 0);
+        queue.push(Reverse((0, *x, *y, id as u32)));
+    }
+    while let Some(Reverse((distance, x, y, id))) = queue.pop() {
+        if ids.get(x, y).copied().flatten().is_some() {
+            continue;
+        }
+        ids.set(x, y, Some(id));
+        for (nx, ny) in height_map.neighbors(x, y) {
+            if !open(nx, ny) || ids.get(nx, ny).copied().flatten().is_some() {
+                continue;
+            }
+            // 10 and 14 steps keep the distances close to euclidean
+            let step = if nx != x && ny != y { 14 } else { 10 };
+            let next = distance + step;
+            if next < *distances.get(nx, ny).unwrap_or(&0) {
+                distances.set(nx, ny, next);
+                queue.push(Reverse((next, nx, ny, id)));
+            }
+        }
+    }
+    ids
+}
+
+/// Moves each seed to the cell of its region closest to the region's centroid.
+fn relax_seeds(ids: &MapLayer<Option<u32>>, seeds: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let centroids = centroids(ids, seeds.len());
+    let mut best = vec![f32::INFINITY; seeds.len()];
+    let mut relaxed = seeds.to_vec();
+    for ((x, y), id) in ids.iter() {
+        if let Some(id) = id {
+            let id = *id as usize;
+            let distance = centroids[id]
+                .0
+                .distance_squared(Vec2::new(x as f32, y as f32));
+            if distance < best[id] {
+                best[id] = distance;
+                relaxed[id] = (x, y);
+            }
+        }
+    }
+    relaxed
+}
+
+/// Mean position and cell count of each region. Sums are kept in `f64`, a
+/// big region adds up to more than `f32` holds exactly.
+fn centroids(ids: &MapLayer<Option<u32>>, count: usize) -> Vec<(Vec2, usize)> {
+    let mut sums = vec![(0., 0., 0); count];
+    for ((x, y), id) in ids.iter() {
+        if let Some(id) = id {
+            let (sum_x, sum_y, cells) = &mut sums[*id as usize];
+            *sum_x += x as f64;
+            *sum_y += y as f64;
+            *cells += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(sum_x, sum_y, cells)| {
+            let n = cells.max(1) as f64;
+            (Vec2::new((sum_x / n) as f32, (sum_y / n) as f32), cells)
+        })
+        .collect()
+}
+
+type Corner = (usize, usize);
+
+/// Collects the cell edges between different ids and joins them into one or
+/// more polylines for each pair of ids. Works on any id layer, lakes use it
+/// for their shores.
+pub fn trace_borders(ids: &MapLayer<Option<u32>>) -> Vec<RegionBorder> {
+    let mut segments: HashMap<RegionPair, Vec<(Corner, Corner)>> = HashMap::default();
+    for ((x, y), id) in ids.iter() {
+        // cell x, y spans corners x, y to x + 1, y + 1
+        let right = ((x + 1, y), ((x + 1, y), (x + 1, y + 1)));
+        let below = ((x, y + 1), ((x, y + 1), (x + 1, y + 1)));
+        for ((nx, ny), segment) in [right, below] {
+            if let Some(other) = ids.get(nx, ny) {
+                if other != id {
+                    let key = (*id.min(other), *id.max(other));
+                    segments.entry(key).or_default().push(segment);
+                }
+            }
+        }
+    }
+
+    let mut borders = Vec::new();
+    for (regions, segments) in segments {
+        for line in join_segments(&segments) {
+            let points = simplify(line)
+                .into_iter()
+                .map(|(x, y)| Vec2::new(x as f32 - 0.5, y as f32 - 0.5))
+                .collect();
+            borders.push(RegionBorder { regions, points });
+        }
+    }
+    borders.sort_by_key(|border| border.regions);
+    borders
+}
+
+fn join_segments(segments: &[(Corner, Corner)]) -> Vec<VecDeque<Corner>> {
+    let mut at: HashMap<Corner, Vec<usize>> = HashMap::default();
+    for (i, (a, b)) in segments.iter().enumerate() {
+        at.entry(*a).or_default().push(i);
+        at.entry(*b).or_default().push(i);
+    }
+    let mut used = vec![false; segments.len()];
+    let next = |corner: Corner, used: &mut Vec<bool>| {
+        let i = *at.get(&corner)?.iter().find(|i| !used[**i])?;
+        used[i] = true;
+        let (a, b) = segments[i];
+        Some(if a == corner { b } else { a })
+    };
+
+    let mut lines = Vec::new();
+    for start in 0..segments.len() {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        let (a, b) = segments[start];
+        let mut line = VecDeque::from([a, b]);
+        while let Some(corner) = next(*line.back().unwrap(), &mut used) {
+            line.push_back(corner);
+        }
+        while let Some(corner) = next(*line.front().unwrap(), &mut used) {
+            line.push_front(corner);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Drops corners in the middle of straight runs.
+fn simplify(line: VecDeque<Corner>) -> Vec<Corner> {
+    let mut points: Vec<Corner> = Vec::with_capacity(line.len());
+    for corner in line {
+        if points.len() >= 2 {
+            let (a, b) = (points[points.len() - 2], points[points.len() - 1]);
+            if (a.0 == b.0 && b.0 == corner.0) || (a.1 == b.1 && b.1 == corner.1) {
+                points.pop();
+            }
+        }
+        points.push(corner);
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 33 by 33 land, with the columns left of `shore` under water at 0.
+    fn island(shore: usize) -> BitImage {
+        let mut height_map = BitImage::new(32);
+        for y in 0..33 {
+            for x in 0..33 {
+                height_map.point_set(x, y, if x < shore { 0. } else { 1. });
+            }
+        }
+        height_map
+    }
+
+    fn regions(height_map: &BitImage, relax_iterations: usize) -> Regions {
+        let settings = RegionSettings {
+            region_count: 12,
+            relax_iterations,
+            land_only: true,
+        };
+        Regions::generate(height_map, &mut RandStruct::from_seed(3), &settings, 0.5)
+    }
+
+    #[test]
+    fn relaxation_keeps_the_region_count_and_covers_the_land() {
+        let height_map = island(0);
+        for relax_iterations in [0, 3] {
+            let regions = regions(&height_map, relax_iterations);
+            assert_eq!(regions.regions.len(), 12);
+            assert!(regions.regions.iter().all(|region| region.cell_count > 0));
+            let cells: usize = regions.regions.iter().map(|r| r.cell_count).sum();
+            assert_eq!(cells, 33 * 33);
+            assert!(regions.ids.iter().all(|(_, id)| id.is_some()));
+        }
+    }
+
+    #[test]
+    fn relaxation_evens_out_region_sizes() {
+        let height_map = island(0);
+        let spread = |regions: Regions| {
+            let sizes = regions.regions.iter().map(|r| r.cell_count);
+            sizes.clone().max().unwrap() - sizes.min().unwrap()
+        };
+        assert!(spread(regions(&height_map, 3)) < spread(regions(&height_map, 0)));
+    }
+
+    #[test]
+    fn adjacency_is_symmetric() {
+        let regions = regions(&island(0), 2);
+        for region in &regions.regions {
+            assert!(!region.neighbors.is_empty());
+            assert!(!regions.are_adjacent(region.id, region.id));
+            for other in &region.neighbors {
+                assert!(regions.are_adjacent(*other, region.id));
+            }
+        }
+        assert!(!regions.are_adjacent(99, 0));
+    }
+
+    #[test]
+    fn water_gets_no_region() {
+        let regions = regions(&island(10), 2);
+        for ((x, _), id) in regions.ids.iter() {
+            assert_eq!(id.is_some(), x >= 10);
+        }
+        assert!(regions.region_at(4, 4).is_none());
+        assert!(regions.region_at(20, 4).is_some());
+    }
+
+    #[test]
+    fn borders_follow_the_cell_edges() {
+        let mut ids = MapLayer::new(4, None);
+        for y in 0..4 {
+            for x in 2..4 {
+                ids.set(x, y, Some(0));
+            }
+        }
+        let borders = trace_borders(&ids);
+        assert_eq!(borders.len(), 1);
+        assert_eq!(borders[0].regions, (None, Some(0)));
+        let mut ends = borders[0].points.clone();
+        ends.sort_by(|a, b| a.y.partial_cmp(&b.y).unwrap());
+        assert_eq!(ends, [Vec2::new(1.5, -0.5), Vec2::new(1.5, 3.5)]);
+    }
+}
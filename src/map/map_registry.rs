@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::Config,
+    map::{
+        step_masked, BitImage, CoastSettings, FalloffMask, HydraulicErosion, MapLayer, MutatorMask,
+        NoiseSettings, PlateSettings, RemapSettings, ReverseRainSettings, SmoothingSettings,
+        Terrace, ThermalErosion,
+    },
+    randstruct::RandStruct,
+};
+
+/// Everything a mutator gets besides the height map itself.
+pub struct MutatorContext<'a> {
+    pub area: Rect<usize>,
+    pub rand: &'a mut RandStruct,
+    /// World height of a height map unit, as in `TerrainSettings`.
+    pub height_scale: f32,
+    /// Loaded image behind `FalloffShape::Mask`, if the noise uses one.
+    pub falloff_mask: Option<&'a FalloffMask>,
+}
+
+/// A stage that changes the height map and nothing else. The mutator holds
+/// its own parameters, and its work is split into units, iterations or
+/// droplets, so long stages can spread over several frames. Progress is the
+/// share of that work done, as the generator's tracker shows it.
+pub trait HeightMapMutator: Send + Sync {
+    /// Name the registry and the menus know the mutator by.
+    fn name(&self) -> &'static str;
+
+    /// Units of work in a full run, 0 when the mutator is switched off.
+    fn work(&self) -> usize {
+        1
+    }
+
+    /// Does some of the work left after `done` units and returns how many
+    /// units it did.
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, done: usize) -> usize;
+}
+
+/// The source mutator of a mask, running on a copy of the map a step at a
+/// time before the masked mutator can start.
+struct MaskPreview {
+    copy: BitImage,
+    /// Seeded from the map seed, so the preview leaves the map's own
+    /// generator where it was.
+    rand: RandStruct,
+    work: usize,
+    done: usize,
+}
+
+/// Height map mutators by name, in the order the generator runs them, and
+/// the masks that limit some of them to part of the map.
+#[derive(Default)]
+pub struct MutatorRegistry {
+    mutators: Vec<Box<dyn HeightMapMutator>>,
+    /// Every stage in run order, with the ones that run as ECS systems.
+    order: Vec<&'static str>,
+    masks: HashMap<String, MutatorMask>,
+    /// Mask weights of the mutator that is running, once its preview is done.
+    weights: Option<MapLayer<f32>>,
+    preview: Option<MaskPreview>,
+    /// Units of work the preview adds in front of the running mutator's own.
+    preview_work: usize,
+}
+
+impl MutatorRegistry {
+    /// Every built-in mutator with its settings loaded from `assets/config`,
+    /// and the masks from `assets/config/masks.cfg`.
+    pub fn load() -> Self {
+        let mut registry = MutatorRegistry::default();
+        registry.register(PlateSettings::load());
+        registry.register(NoiseSettings::load());
+        registry.register(RemapSettings::load());
+        registry.register_systems(ReverseRainSettings::NAME);
+        registry.register(HydraulicErosion::default());
+        registry.register(ThermalErosion::default());
+        registry.register(SmoothingSettings::load());
+        registry.register(Terrace::load());
+        registry.register(CoastSettings::load());
+        if let Some(config) = Config::load("masks") {
+            for name in config.keys() {
+                if registry.get(name).is_none() {
+                    warn!("masks.cfg: no height map mutator called {}", name);
+                    continue;
+                }
+                match config.get_str(name).unwrap_or_default().parse() {
+                    Ok(mask) => registry.set_mask(name, Some(mask)),
+                    Err(e) => warn!("masks.cfg: {}: {}", name, e),
+                }
+            }
+        }
+        registry
+    }
+
+    /// Adds `mutator` at the end, or in place of the one registered under the
+    /// same name.
+    pub fn register<M: HeightMapMutator + 'static>(&mut self, mutator: M) {
+        let name = mutator.name();
+        match self.mutators.iter().position(|m| m.name() == name) {
+            Some(i) => self.mutators[i] = Box::new(mutator),
+            None => self.mutators.push(Box::new(mutator)),
+        }
+        self.register_systems(name);
+    }
+
+    /// Adds a place at the end of the run order for a stage that runs as ECS
+    /// systems of its own, like reverse rain. The generator drives it when
+    /// its turn comes, `get` doesn't know it and it can't be masked.
+    pub fn register_systems(&mut self, name: &'static str) {
+        if !self.order.contains(&name) {
+            self.order.push(name);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn HeightMapMutator> {
+        self.mutators
+            .iter()
+            .find(|m| m.name() == name)
+            .map(|m| m.as_ref())
+    }
+
+    /// Every stage in run order.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.order.iter().copied()
+    }
+
+    pub fn mask(&self, name: &str) -> Option<&MutatorMask> {
+        self.masks.get(name)
+    }
+
+    /// Puts `name` behind `mask`, or back on the whole area with `None`.
+    pub fn set_mask(&mut self, name: &str, mask: Option<MutatorMask>) {
+        match mask {
+            Some(mask) => self.masks.insert(name.to_string(), mask),
+            None => self.masks.remove(name),
+        };
+    }
+
+    /// Units of work in a full run of `name`, including the preview of its
+    /// mask's source mutator. 0 when `name` is switched off.
+    pub fn work(&self, name: &str) -> Option<usize> {
+        let work = self.get(name)?.work();
+        if work == 0 {
+            return Some(0);
+        }
+        let preview = self
+            .mask(name)
+            .and_then(MutatorMask::source_mutator)
+            .and_then(|source| self.get(source))
+            .map_or(0, |source| source.work());
+        Some(preview + work)
+    }
+
+    /// Sets up the mask of `name` from the heights as they are, before its
+    /// first step. A mask read from another mutator first runs that one on a
+    /// copy of the map, through `step`.
+    pub fn start(&mut self, name: &str, height_map: &BitImage, ctx: &mut MutatorContext) {
+        self.weights = None;
+        self.preview = None;
+        self.preview_work = 0;
+        let mask = match self.masks.get(name) {
+            Some(mask) => mask,
+            None => return,
+        };
+        let source = mask
+            .source_mutator()
+            .map(|source| (source, self.get(source)));
+        let (weights, preview) = match source {
+            Some((_, Some(source))) if source.work() > 0 => {
+                let preview = MaskPreview {
+                    copy: height_map.clone(),
+                    rand: RandStruct::from_seed(ctx.rand.map_seed()),
+                    work: source.work(),
+                    done: 0,
+                };
+                (None, Some(preview))
+            }
+            Some((_, Some(_))) => (Some(mask.weights(height_map, Some(height_map), ctx)), None),
+            Some((source, None)) => {
+                warn!("Mask source: no height map mutator called {}", source);
+                (Some(mask.weights(height_map, None, ctx)), None)
+            }
+            None => (Some(mask.weights(height_map, None, ctx)), None),
+        };
+        self.preview_work = preview.as_ref().map_or(0, |preview| preview.work);
+        self.weights = weights;
+        self.preview = preview;
+    }
+
+    /// Does a step of the mask preview of `name` while it lasts, then of
+    /// `name` itself through the mask weights, and returns how many units of
+    /// work it did. `done` counts the preview's units too.
+    pub fn step(
+        &mut self,
+        name: &str,
+        height_map: &mut BitImage,
+        ctx: &mut MutatorContext,
+        done: usize,
+    ) -> usize {
+        if let Some(mut preview) = self.preview.take() {
+            let mask = self.masks.get(name);
+            let source = mask
+                .and_then(MutatorMask::source_mutator)
+                .and_then(|source| self.get(source));
+            let (mask, source) = match (mask, source) {
+                (Some(mask), Some(source)) => (mask, source),
+                _ => return 0,
+            };
+            let mut preview_ctx = MutatorContext {
+                area: ctx.area,
+                rand: &mut preview.rand,
+                height_scale: ctx.height_scale,
+                falloff_mask: ctx.falloff_mask,
+            };
+            let did = source
+                .step(&mut preview.copy, &mut preview_ctx, preview.done)
+                .max(1);
+            preview.done += did;
+            if preview.done >= preview.work {
+                self.weights = Some(mask.weights(height_map, Some(&preview.copy), ctx));
+            } else {
+                self.preview = Some(preview);
+            }
+            return did;
+        }
+        let mutator = match self.get(name) {
+            Some(mutator) => mutator,
+            None => return 0,
+        };
+        let done = done.saturating_sub(self.preview_work);
+        match &self.weights {
+            Some(weights) => step_masked(mutator, weights, height_map, ctx, done),
+            None => mutator.step(height_map, ctx, done),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::{parse_numbers, Config},
+    map::{BitImage, HeightMapMutator, MutatorContext},
+};
+
+/// How the remap curve runs between its control points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemapInterpolation {
+    Linear,
+    /// Smooth cubic that never overshoots the control points, so a rising
+    /// curve keeps rising everywhere between them.
+    Spline,
+}
+
+impl RemapInterpolation {
+    pub const ALL: [RemapInterpolation; 2] =
+        [RemapInterpolation::Linear, RemapInterpolation::Spline];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RemapInterpolation::Linear => "linear",
+            RemapInterpolation::Spline => "spline",
+        }
+    }
+}
+
+impl FromStr for RemapInterpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RemapInterpolation::ALL
+            .into_iter()
+            .find(|interpolation| interpolation.name() == s)
+            .ok_or(format!("Unknown remap interpolation: {}", s))
+    }
+}
+
+/// Redistributes heights through a curve, after an optional histogram
+/// equalization. Heights are normalized to 0..1 between the lowest and the
+/// highest cell of the area on the way in, and stretched back on the way out.
+/// Loaded from `assets/config/remap.cfg` at startup.
+pub struct RemapSettings {
+    /// `[in, out]` control points, in normalized height and in order of `in`.
+    /// Empty leaves the heights as the equalization left them.
+    pub curve: Vec<[f32; 2]>,
+    pub interpolation: RemapInterpolation,
+    /// Share of histogram equalization mixed in first, 0..1, 0 turns it off.
+    /// Fully equalized heights are spread evenly over the range, so the curve
+    /// then decides how much of the map ends up at each height.
+    pub equalize: f32,
+    /// Height bins of the equalization histogram.
+    pub bins: usize,
+}
+
+impl Default for RemapSettings {
+    fn default() -> Self {
+        RemapSettings {
+            curve: Vec::new(),
+            interpolation: RemapInterpolation::Spline,
+            equalize: 0.,
+            bins: 256,
+        }
+    }
+}
+
+impl RemapSettings {
+    pub fn load() -> Self {
+        let mut settings = RemapSettings::default();
+        if let Some(config) = Config::load("remap") {
+            config.read_list("curve", &mut settings.curve, parse_numbers);
+            config.read("interpolation", &mut settings.interpolation);
+            config.read("equalize", &mut settings.equalize);
+            config.read("bins", &mut settings.bins);
+        }
+        settings
+    }
+
+    /// The curve at `t`, holding the first and last values past the ends.
+    pub fn sample(&self, t: f32) -> f32 {
+        let points = &self.curve;
+        match points.as_slice() {
+            [] => return t,
+            [[_, y]] => return *y,
+            _ => (),
+        }
+        let last = points.len() - 1;
+        if t <= points[0][0] {
+            return points[0][1];
+        }
+        if t >= points[last][0] {
+            return points[last][1];
+        }
+        let i = points
+            .windows(2)
+            .position(|pair| t <= pair[1][0])
+            .unwrap_or(last - 1);
+        let ([x0, y0], [x1, y1]) = (points[i], points[i + 1]);
+        let width = x1 - x0;
+        if width <= 0. {
+            return y1;
+        }
+        let f = (t - x0) / width;
+        match self.interpolation {
+            RemapInterpolation::Linear => y0 + (y1 - y0) * f,
+            RemapInterpolation::Spline => {
+                let (m0, m1) = (self.tangent(i), self.tangent(i + 1));
+                let (f2, f3) = (f * f, f * f * f);
+                (2. * f3 - 3. * f2 + 1.) * y0
+                    + (f3 - 2. * f2 + f) * width * m0
+                    + (-2. * f3 + 3. * f2) * y1
+                    + (f3 - f2) * width * m1
+            }
+        }
+    }
+
+    /// Slope of the curve between control points `i` and `i + 1`.
+    fn secant(&self, i: usize) -> f32 {
+        let ([x0, y0], [x1, y1]) = (self.curve[i], self.curve[i + 1]);
+        if x1 > x0 {
+            (y1 - y0) / (x1 - x0)
+        } else {
+            0.
+        }
+    }
+
+    /// Spline tangent at control point `i`, flattened where the curve turns
+    /// and limited so the segments next to it can't overshoot (Fritsch-Carlson).
+    fn tangent(&self, i: usize) -> f32 {
+        let last = self.curve.len() - 1;
+        let before = if i > 0 {
+            Some(self.secant(i - 1))
+        } else {
+            None
+        };
+        let after = if i < last { Some(self.secant(i)) } else { None };
+        match (before, after) {
+            (Some(d0), Some(d1)) => {
+                if d0 * d1 <= 0. {
+                    return 0.;
+                }
+                let m = (d0 + d1) / 2.;
+                // both neighboring segments must stay within 3 times their slope
+                m.signum() * m.abs().min(3. * d0.abs()).min(3. * d1.abs())
+            }
+            (Some(d), None) | (None, Some(d)) => d,
+            (None, None) => 0.,
+        }
+    }
+
+    /// Maps each normalized height, 0..1, to the share of `normalized` that
+    /// lies below it, read off a histogram of `bins` bins.
+    fn cumulative(&self, normalized: &[f32]) -> Vec<f32> {
+        let bins = self.bins.max(1);
+        let bin = |t: f32| ((t * bins as f32) as usize).min(bins - 1);
+        let mut counts = vec![0usize; bins];
+        for t in normalized.iter() {
+            counts[bin(*t)] += 1;
+        }
+        let total = normalized.len().max(1) as f32;
+        let mut below = vec![0.; bins + 1];
+        for i in 0..bins {
+            below[i + 1] = below[i] + counts[i] as f32 / total;
+        }
+        normalized
+            .iter()
+            .map(|t| {
+                let i = bin(*t);
+                let f = (t * bins as f32 - i as f32).clamp(0., 1.);
+                below[i] + (below[i + 1] - below[i]) * f
+            })
+            .collect()
+    }
+
+    pub fn run_mutate(&self, height_map: &mut BitImage, area: Rect<usize>) {
+        let mut heights = Vec::new();
+        for y in area.top..(area.bottom + 1) {
+            for x in area.left..(area.right + 1) {
+                heights.push(height_map.get_ignore(x, y));
+            }
+        }
+        let low = heights.iter().copied().fold(f32::INFINITY, f32::min);
+        let high = heights.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let range = high - low;
+        if range <= 0. {
+            return;
+        }
+        let normalized: Vec<f32> = heights.iter().map(|h| (h - low) / range).collect();
+        let equalized = if self.equalize > 0. {
+            self.cumulative(&normalized)
+        } else {
+            normalized.clone()
+        };
+        let width = area.right - area.left + 1;
+        for (i, (t, e)) in normalized.iter().zip(equalized).enumerate() {
+            let t = t + (e - t) * self.equalize.clamp(0., 1.);
+            let (x, y) = (area.left + i % width, area.top + i / width);
+            height_map.point_set(x, y, low + self.sample(t) * range);
+        }
+    }
+}
+
+impl HeightMapMutator for RemapSettings {
+    fn name(&self) -> &'static str {
+        "remap"
+    }
+
+    fn work(&self) -> usize {
+        usize::from(!self.curve.is_empty() || self.equalize > 0.)
+    }
+
+    fn step(&self, height_map: &mut BitImage, ctx: &mut MutatorContext, _done: usize) -> usize {
+        self.run_mutate(height_map, ctx.area);
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(curve: Vec<[f32; 2]>, interpolation: RemapInterpolation) -> RemapSettings {
+        RemapSettings {
+            curve,
+            interpolation,
+            ..RemapSettings::default()
+        }
+    }
+
+    #[test]
+    fn sample_without_a_curve_is_the_identity() {
+        let remap = RemapSettings::default();
+        for t in [0., 0.25, 1.] {
+            assert_eq!(remap.sample(t), t);
+        }
+    }
+
+    #[test]
+    fn sample_holds_the_end_values() {
+        let remap = settings(vec![[0.2, 0.1], [0.8, 0.9]], RemapInterpolation::Linear);
+        assert_eq!(remap.sample(0.), 0.1);
+        assert_eq!(remap.sample(1.), 0.9);
+        assert!((remap.sample(0.5) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spline_passes_through_the_control_points() {
+        let curve = vec![[0., 0.], [0.3, 0.05], [0.7, 0.6], [1., 1.]];
+        let remap = settings(curve.clone(), RemapInterpolation::Spline);
+        for [x, y] in curve {
+            assert!(
+                (remap.sample(x) - y).abs() < 1e-6,
+                "{} {}",
+                x,
+                remap.sample(x)
+            );
+        }
+    }
+
+    #[test]
+    fn spline_never_overshoots_a_rising_curve() {
+        // a flat stretch next to a steep one is where a plain cubic overshoots
+        let curve = vec![[0., 0.], [0.4, 0.], [0.5, 1.], [1., 1.]];
+        let remap = settings(curve, RemapInterpolation::Spline);
+        let samples: Vec<f32> = (0..=100).map(|i| remap.sample(i as f32 / 100.)).collect();
+        assert!(samples.windows(2).all(|pair| pair[1] >= pair[0] - 1e-6));
+        assert!(samples.iter().all(|s| (-1e-6..=1. + 1e-6).contains(s)));
+    }
+
+    #[test]
+    fn equalize_spreads_heights_evenly() {
+        let mut image = BitImage::new(64);
+        let size = image.edge_size();
+        for y in 0..size {
+            for x in 0..size {
+                // most of the map low, a few peaks
+                let t = (y * size + x) as f32 / (size * size - 1) as f32;
+                image.point_set(x, y, t.powi(4));
+            }
+        }
+        let remap = RemapSettings {
+            equalize: 1.,
+            ..RemapSettings::default()
+        };
+        let last = size - 1;
+        let area = Rect {
+            left: 0,
+            top: 0,
+            right: last,
+            bottom: last,
+        };
+        remap.run_mutate(&mut image, area);
+        let median = image.height_quantile(0.5);
+        assert!((median - 0.5).abs() < 0.02, "{}", median);
+    }
+}
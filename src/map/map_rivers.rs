@@ -0,0 +1,300 @@
+use bevy::prelude::*;
+use noise::{NoiseFn, Perlin, Seedable};
+
+use crate::{
+    config::Config,
+    map::{BitImage, Boundary, FlowField, NO_RECEIVER},
+    randstruct::RandStruct,
+};
+
+const NO_RIVER: usize = usize::MAX;
+
+/// River placement and channel shape. Loaded from `assets/config/rivers.cfg`
+/// at startup.
+pub struct RiverSettings {
+    pub max_rivers: usize,
+    /// Lowest ground, in height map units, a river can start from.
+    pub source_height: f32,
+    /// Upstream cells needed before the rain gathers into a river, which picks
+    /// the wet cells among the high ones.
+    pub source_flow: u32,
+    /// Rivers shorter than this many cells are dropped.
+    pub min_length: usize,
+    /// Channel half width in cells at the source, grows with the square root
+    /// of the flow.
+    pub width: f32,
+    pub max_width: f32,
+    /// Channel depth in height map units at the source, grows like the width.
+    pub depth: f32,
+    pub max_depth: f32,
+    /// How far, in cells, the channel swings away from the straight course.
+    pub meander: f32,
+    /// Bends per cell of river length.
+    pub meander_frequency: f64,
+}
+
+impl Default for RiverSettings {
+    fn default() -> Self {
+        RiverSettings {
+            max_rivers: 40,
+            source_height: 0.05,
+            source_flow: 150,
+            min_length: 24,
+            width: 1.,
+            max_width: 6.,
+            depth: 0.002,
+            max_depth: 0.015,
+            meander: 3.,
+            meander_frequency: 0.05,
+        }
+    }
+}
+
+impl RiverSettings {
+    pub fn load() -> Self {
+        let mut settings = RiverSettings::default();
+        if let Some(config) = Config::load("rivers") {
+            config.read("max_rivers", &mut settings.max_rivers);
+            config.read("source_height", &mut settings.source_height);
+            config.read("source_flow", &mut settings.source_flow);
+            config.read("min_length", &mut settings.min_length);
+            config.read("width", &mut settings.width);
+            config.read("max_width", &mut settings.max_width);
+            config.read("depth", &mut settings.depth);
+            config.read("max_depth", &mut settings.max_depth);
+            config.read("meander", &mut settings.meander);
+            config.read("meander_frequency", &mut settings.meander_frequency);
+        }
+        settings
+    }
+}
+
+/// Where a river ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RiverMouth {
+    /// Reaches the water height.
+    Sea,
+    /// Runs into a pit above the sea, where a lake collects.
+    Lake,
+    /// Flows into the river with this index, at its own last cell.
+    Joins(usize),
+}
+
+#[allow(dead_code)]
+pub struct River {
+    /// Course through the grid from source to mouth, one cell after another.
+    pub cells: Vec<(usize, usize)>,
+    /// Meandering course in cell coordinates, one point per cell.
+    pub points: Vec<Vec2>,
+    /// Upstream cells draining through each point. The segment from
+    /// `points[i]` to `points[i + 1]` carries `flow[i]`.
+    pub flow: Vec<u32>,
+    pub mouth: RiverMouth,
+}
+
+/// River graph, rivers are linked through `RiverMouth::Joins`.
+pub struct Rivers {
+    pub rivers: Vec<River>,
+}
+
+#[allow(dead_code)]
+impl Rivers {
+    /// Starts a river at the head of every stream on high ground, highest
+    /// first, and follows the flow downhill until it reaches the sea, a pit
+    /// or a river traced before it.
+    pub fn trace(
+        height_map: &BitImage,
+        rand: &mut RandStruct,
+        settings: &RiverSettings,
+        water_level: f32,
+    ) -> Self {
+        let heights: Vec<f32> = height_map.get_heightmap_iter().collect();
+        let field = FlowField::new(height_map, &heights);
+        let size = height_map.edge_size();
+        let is_stream = |cell: usize| field.flow[cell] >= settings.source_flow;
+
+        let mut fed = vec![false; heights.len()];
+        for cell in 0..heights.len() {
+            if is_stream(cell) && field.receiver[cell] != NO_RECEIVER {
+                fed[field.receiver[cell]] = true;
+            }
+        }
+        let mut sources: Vec<usize> = (0..heights.len())
+            .filter(|&c| is_stream(c) && !fed[c] && heights[c] >= settings.source_height)
+            .collect();
+        sources.sort_by(|a, b| heights[*b].total_cmp(&heights[*a]).then(a.cmp(b)));
+
+        let noise = Perlin::new().set_seed(rand.get_map_u32());
+        let mut owner = vec![NO_RIVER; heights.len()];
+        let mut rivers = Vec::new();
+        for source in sources {
+            if rivers.len() >= settings.max_rivers {
+                break;
+            }
+            let mut path = Vec::new();
+            let mut cell = source;
+            let mouth = loop {
+                if owner[cell] != NO_RIVER {
+                    break RiverMouth::Joins(owner[cell]);
+                }
+                path.push(cell);
+                if heights[cell] <= water_level {
+                    break RiverMouth::Sea;
+                }
+                cell = field.receiver[cell];
+                if cell == NO_RECEIVER {
+                    break RiverMouth::Lake;
+                }
+            };
+            if path.len() < settings.min_length {
+                continue;
+            }
+            for &c in path.iter() {
+                owner[c] = rivers.len();
+            }
+            if let RiverMouth::Joins(_) = mouth {
+                path.push(cell);
+            }
+
+            let cells: Vec<(usize, usize)> = path.iter().map(|c| (c % size, c / size)).collect();
+            let offset = rivers.len() as f64 * 10.;
+            rivers.push(River {
+                points: meander(&cells, &noise, settings, offset),
+                flow: path.iter().map(|c| field.flow[*c]).collect(),
+                cells,
+                mouth,
+            });
+        }
+        Rivers { rivers }
+    }
+
+    /// Digs a channel along every river. The bed only ever goes down on the
+    /// way to the mouth, so carved rivers don't pool halfway.
+    pub fn carve(&self, height_map: &mut BitImage, settings: &RiverSettings) {
+        for river in self.rivers.iter() {
+            let mut bed = f32::MAX;
+            for (i, point) in river.points.iter().enumerate() {
+                let strength = (river.flow[i] as f32 / settings.source_flow as f32).sqrt();
+                let radius = (settings.width * strength).min(settings.max_width);
+                let depth = (settings.depth * strength).min(settings.max_depth);
+                let (x, y) = river.cells[i];
+                bed = bed.min(height_map.get_ignore(x, y) - depth);
+                carve_disc(height_map, *point, radius, depth, bed);
+            }
+        }
+    }
+
+    /// Indices of the rivers that flow into `river`.
+    pub fn tributaries(&self, river: usize) -> impl Iterator<Item = usize> + '_ {
+        self.rivers
+            .iter()
+            .enumerate()
+            .filter(move |(_, r)| r.mouth == RiverMouth::Joins(river))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Smooths out the zigzag of the grid path and swings it from side to side,
+/// pinned at both ends so the river still leaves its source and meets its mouth.
+fn meander(
+    cells: &[(usize, usize)],
+    noise: &Perlin,
+    settings: &RiverSettings,
+    offset: f64,
+) -> Vec<Vec2> {
+    let raw: Vec<Vec2> = cells
+        .iter()
+        .map(|(x, y)| Vec2::new(*x as f32, *y as f32))
+        .collect();
+    let last = raw.len() - 1;
+    let smooth: Vec<Vec2> = (0..raw.len())
+        .map(|i| (raw[i.saturating_sub(1)] + raw[i] * 2. + raw[(i + 1).min(last)]) / 4.)
+        .collect();
+
+    let mut along = 0.;
+    (0..raw.len())
+        .map(|i| {
+            if i > 0 {
+                along += raw[i].distance(raw[i - 1]);
+            }
+            let tangent =
+                (smooth[(i + 1).min(last)] - smooth[i.saturating_sub(1)]).normalize_or_zero();
+            let normal = Vec2::new(-tangent.y, tangent.x);
+            let taper = (i.min(last - i) as f32 / 8.).min(1.);
+            let swing = noise.get([along as f64 * settings.meander_frequency, offset]) as f32;
+            smooth[i] + normal * swing * settings.meander * taper
+        })
+        .collect()
+}
+
+/// Lowers the ground around `center` to a round channel with its deepest
+/// point at `bed`, never raising anything. On a wrapping map the channel
+/// carries on across the edge.
+fn carve_disc(height_map: &mut BitImage, center: Vec2, radius: f32, depth: f32, bed: f32) {
+    let reach = radius + 1.;
+    let r = reach.ceil() as isize;
+    let (cx, cy) = (center.x.round() as isize, center.y.round() as isize);
+    let last = height_map.edge_size() - 1;
+    for dy in -r..=r {
+        for dx in -r..=r {
+            let (x, y) = (cx + dx, cy + dy);
+            let distance = Vec2::new(x as f32, y as f32).distance(center);
+            if distance > reach {
+                continue;
+            }
+            let (x, y) = match height_map.wrap_coords(x, y) {
+                // the last row and column repeat the first ones, carving the
+                // first lets `match_edges` carry it over
+                Some((x, y)) if height_map.boundary() == Boundary::Wrap => (x % last, y % last),
+                Some(cell) => cell,
+                None => continue,
+            };
+            let t = distance / reach;
+            let target = bed + depth * t * t;
+            if target < height_map.get_ignore(x, y) {
+                height_map.point_set(x, y, target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(boundary: Boundary) -> BitImage {
+        let mut height_map = BitImage::new(16);
+        height_map.set_boundary(boundary);
+        for y in 0..17 {
+            for x in 0..17 {
+                height_map.point_set(x, y, 1.);
+            }
+        }
+        height_map
+    }
+
+    #[test]
+    fn channels_carry_on_across_a_wrapping_edge() {
+        let mut height_map = flat(Boundary::Wrap);
+        carve_disc(&mut height_map, Vec2::new(16., 8.), 2., 0.2, 0.5);
+        height_map.match_edges();
+        // 16 is the same column as 0, so 17 is 1 and 15 stays 15
+        for x in [0, 1, 15, 16] {
+            assert!(height_map.get_ignore(x, 8) < 1., "{}", x);
+        }
+        assert_eq!(height_map.get_ignore(0, 8), 0.5);
+        assert_eq!(height_map.get_ignore(1, 8), height_map.get_ignore(15, 8));
+        for y in 0..17 {
+            assert_eq!(height_map.get_ignore(0, y), height_map.get_ignore(16, y));
+        }
+    }
+
+    #[test]
+    fn channels_stop_at_a_clamped_edge() {
+        let mut height_map = flat(Boundary::Clamp);
+        carve_disc(&mut height_map, Vec2::new(16., 8.), 2., 0.2, 0.5);
+        assert_eq!(height_map.get_ignore(16, 8), 0.5);
+        assert!(height_map.get_ignore(15, 8) < 1.);
+        assert_eq!(height_map.get_ignore(1, 8), 1.);
+    }
+}
@@ -1,7 +1,45 @@
+mod map_biomes;
+mod map_brush;
+mod map_climate;
 mod map_data;
+mod map_diamond_square;
+mod map_erosion;
+mod map_falloff;
+mod map_features;
+mod map_filters;
+mod map_flow;
+mod map_image;
 mod map_iters;
+mod map_lakes;
+mod map_layer;
+mod map_mask;
 mod map_mutators;
+mod map_noise;
+mod map_plates;
+mod map_regions;
+mod map_registry;
+mod map_remap;
+mod map_rivers;
 
-pub use map_data::{BitImage, WorldDataPlugin};
+pub use map_biomes::*;
+pub use map_brush::*;
+pub use map_climate::*;
+pub use map_data::{BitImage, Boundary, WorldDataPlugin};
+pub use map_diamond_square::*;
+pub use map_erosion::*;
+pub use map_falloff::*;
+pub use map_features::*;
+pub use map_filters::*;
+pub use map_flow::*;
+pub use map_image::*;
 pub use map_iters::*;
+pub use map_lakes::*;
+pub use map_layer::*;
+pub use map_mask::*;
 pub use map_mutators::*;
+pub use map_noise::*;
+pub use map_plates::*;
+pub use map_regions::*;
+pub use map_registry::*;
+pub use map_remap::*;
+pub use map_rivers::*;
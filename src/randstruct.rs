@@ -19,6 +19,16 @@ impl RandStruct {
         RandStruct { god, map, map_seed }
     }
 
+    /// Starts both generators from `map_seed`, for side runs that must not
+    /// move the map generator on.
+    pub fn from_seed(map_seed: u64) -> Self {
+        RandStruct {
+            god: Rand32::new(map_seed),
+            map: Rand32::new(map_seed),
+            map_seed,
+        }
+    }
+
     pub fn get_map_float(&mut self) -> f32 {
         self.map.rand_float()
     }
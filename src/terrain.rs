@@ -2,11 +2,16 @@ use bevy::{
     prelude::*,
     render::{
         mesh::{Indices, VertexAttributeValues},
-        render_resource::PrimitiveTopology,
+        render_resource::{Extent3d, PrimitiveTopology, TextureDimension, TextureFormat},
     },
+    utils::HashMap,
 };
 
-use crate::{generation::Tracker, map::BitImage};
+use crate::{
+    config::Config,
+    generation::Tracker,
+    map::{Biomes, BitImage, Lake, Lakes},
+};
 
 pub struct TerrainPlugin;
 
@@ -14,7 +19,12 @@ pub struct TerrainSettings {
     pub unit_count: usize,
     pub unit_size: f32,
     pub height_scale: f32,
+    /// Sea level in world units. Generation overwrites it when
+    /// `land_fraction` is set.
     pub water_height: f32,
+    /// Share of the cells, 0..1, to keep above the sea by picking the water
+    /// height to match, 0 keeps `water_height` as it is.
+    pub land_fraction: f32,
 }
 
 pub struct TerrainMesh {
@@ -23,7 +33,7 @@ pub struct TerrainMesh {
 
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<TerrainSettings>()
+        app.insert_resource(TerrainSettings::load())
             .init_resource::<TerrainMesh>();
     }
 }
@@ -44,21 +54,65 @@ impl Default for TerrainSettings {
             unit_size: 1.,
             height_scale: 300.,
             water_height: 5.,
+            land_fraction: 0.,
+        }
+    }
+}
+
+impl TerrainSettings {
+    /// Reads the sea level from `assets/config/water.cfg`.
+    pub fn load() -> Self {
+        let mut settings = TerrainSettings::default();
+        if let Some(config) = Config::load("water") {
+            config.read("water_height", &mut settings.water_height);
+            config.read("land_fraction", &mut settings.land_fraction);
+        }
+        settings
+    }
+
+    /// World height of a height map unit, as the terrain mesh lays it out.
+    pub fn world_scale(&self) -> f32 {
+        self.unit_size * self.height_scale
+    }
+
+    /// Water height in height map units.
+    pub fn water_level(&self) -> f32 {
+        self.water_height / self.world_scale()
+    }
+
+    /// Moves the sea to leave `land_fraction` of `height_map` above it, when
+    /// `land_fraction` is set.
+    pub fn calibrate_water(&mut self, height_map: &BitImage) {
+        if self.land_fraction > 0. {
+            let level = height_map.height_quantile(1. - self.land_fraction);
+            self.water_height = level * self.world_scale();
         }
     }
 }
 
+/// Spawns the terrain, colored by biome when the map has biomes, and the
+/// water and ground planes around it.
 pub fn terrain_startup(
     mut commands: Commands,
     terrain_data: Res<TerrainMesh>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    biomes: Option<Res<Biomes>>,
     terrain_settings: Res<TerrainSettings>,
 ) {
+    let (base_color, base_color_texture) = match biomes {
+        Some(biomes) => (
+            Color::WHITE,
+            Some(images.add(biome_texture(biomes.as_ref()))),
+        ),
+        None => (Color::rgb(0.3, 0.5, 0.3), None),
+    };
     commands.spawn_bundle(PbrBundle {
         mesh: terrain_data.mesh_handle.clone(),
         material: materials.add(StandardMaterial {
-            base_color: Color::rgb(0.3, 0.5, 0.3),
+            base_color,
+            base_color_texture,
             metallic: 0.,
             reflectance: 0.1,
             perceptual_roughness: 0.9,
@@ -96,6 +150,107 @@ pub fn terrain_startup(
     });
 }
 
+/// One texel per height map cell, in the biome's color. `terrain_build` lays
+/// it over the mesh with a texel centered on every vertex.
+fn biome_texture(biomes: &Biomes) -> Image {
+    let size = biomes.ids.edge_size();
+    let data = biomes
+        .ids
+        .iter()
+        .flat_map(|((x, y), _)| {
+            let [r, g, b] = biomes.color(x, y);
+            [r, g, b, 255]
+        })
+        .collect();
+    Image::new(
+        Extent3d {
+            width: size as u32,
+            height: size as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/// Spawns a flat water surface over every lake, each at its own level. The
+/// sea is still covered by the water plane from `terrain_startup`.
+pub fn spawn_lakes(
+    mut commands: Commands,
+    lakes: Option<Res<Lakes>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    terrain_settings: Res<TerrainSettings>,
+) {
+    let lakes = match lakes {
+        Some(lakes) => lakes,
+        None => return,
+    };
+    let material = materials.add(StandardMaterial {
+        base_color: Color::rgba(0.3, 0.4, 1., 0.25),
+        alpha_mode: AlphaMode::Blend,
+        ..Default::default()
+    });
+    let unit_size = terrain_settings.unit_size;
+    for lake in lakes.lakes.iter() {
+        let height = lake.level * terrain_settings.world_scale();
+        commands.spawn_bundle(PbrBundle {
+            mesh: meshes.add(lake_mesh(lake, terrain_settings.unit_count, unit_size)),
+            material: material.clone(),
+            transform: Transform::from_xyz(0., height, 0.),
+            ..Default::default()
+        });
+    }
+}
+
+/// Covers every grid square that has a lake cell in one of its corners, so
+/// the water reaches up the shore and the ground hides the overhang.
+fn lake_mesh(lake: &Lake, size: usize, unit_size: f32) -> Mesh {
+    let mut squares = Vec::with_capacity(lake.cells.len());
+    for &(x, y) in lake.cells.iter() {
+        let (left, up) = (x.wrapping_sub(1), y.wrapping_sub(1));
+        for (sx, sy) in [(left, up), (x, up), (left, y), (x, y)] {
+            if sx < size && sy < size {
+                squares.push((sx, sy));
+            }
+        }
+    }
+    squares.sort_unstable();
+    squares.dedup();
+
+    let mut corner_index = HashMap::default();
+    let mut vertices: Vec<[f32; 3]> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    for (sx, sy) in squares {
+        let mut corner = |cx: usize, cy: usize| {
+            *corner_index.entry((cx, cy)).or_insert_with(|| {
+                vertices.push([cx as f32 * unit_size, 0., cy as f32 * unit_size]);
+                vertices.len() as u32 - 1
+            })
+        };
+        let (a, b) = (corner(sx, sy), corner(sx + 1, sy));
+        let (c, d) = (corner(sx, sy + 1), corner(sx + 1, sy + 1));
+        // same winding as the terrain mesh
+        indices.extend([a, d, b, a, c, d]);
+    }
+
+    let normals = vec![[0., 1., 0.]; vertices.len()];
+    let uvs = vec![[0., 0.]; vertices.len()];
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        VertexAttributeValues::Float32x3(vertices),
+    );
+    mesh.set_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        VertexAttributeValues::Float32x3(normals),
+    );
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, VertexAttributeValues::Float32x2(uvs));
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
 pub fn terrain_build(
     terrain_settings: Res<TerrainSettings>,
     terrain_data: Res<TerrainMesh>,
@@ -115,8 +270,7 @@ pub fn terrain_build(
 
     vertices.resize(vertex_number, [0.0f32, 0.0f32, 0.0f32]);
     normals.resize(vertex_number, [0.0f32, 1.0f32, 0.0f32]);
-    // TODO: update UV coords to correct 00, 01, 10, 11 coords
-    let uvs = vec![[0.0, 0.0]; vertices.len()];
+    let mut uvs: Vec<[f32; 2]> = Vec::with_capacity(vertex_number);
 
     // vertex
     let mut vertex_index = 0;
@@ -124,8 +278,11 @@ pub fn terrain_build(
         for cx in 0..(size + 1) {
             // do height here (debug wave)
             // let h = ((cx + cy) as f32 / 4.).sin();
-            let h = heightmap.get(cx, cy).unwrap() * unit_size * terrain_settings.height_scale;
+            let h = heightmap.get(cx, cy).unwrap() * terrain_settings.world_scale();
             vertices[vertex_index] = [cx as f32 * unit_size, h, cy as f32 * unit_size];
+            // centered on the cell's texel in the biome texture
+            let texels = (size + 1) as f32;
+            uvs.push([(cx as f32 + 0.5) / texels, (cy as f32 + 0.5) / texels]);
             vertex_index += 1;
         }
     }
@@ -200,3 +357,32 @@ pub fn terrain_build(
 
     tracker.add_progress(100.);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibrated_sea_meets_the_mesh_at_the_land_fraction() {
+        let mut height_map = BitImage::new(9);
+        for y in 0..10 {
+            for x in 0..10 {
+                height_map.point_set(x, y, (y * 10 + x) as f32 / 100.);
+            }
+        }
+        let mut settings = TerrainSettings {
+            unit_size: 2.5,
+            land_fraction: 0.35,
+            ..TerrainSettings::default()
+        };
+        settings.calibrate_water(&height_map);
+
+        // the same heights the mesh gets
+        let land = height_map
+            .get_heightmap_iter()
+            .filter(|h| h * settings.world_scale() > settings.water_height)
+            .count();
+        assert_eq!(land, 35);
+        assert!((settings.water_level() - height_map.height_quantile(0.65)).abs() < 1e-6);
+    }
+}